@@ -0,0 +1,265 @@
+// Parsing of the date formats git accepts for --date, a useful subset of
+// git's approxidate: RFC 2822, ISO 8601, raw timestamps and relative dates
+// like "2 weeks ago".
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+// Parse a date relative to now. Dates without a timezone are taken to be
+// in the timezone of now.
+pub fn parse(spec: &str, now: git2::Time) -> Option<git2::Time> {
+    let spec = spec.trim();
+    let lower = spec.to_ascii_lowercase();
+
+    match lower.as_str() {
+        "now" => return Some(now),
+        "yesterday" => return Some(git2::Time::new(now.seconds() - 86400, now.offset_minutes())),
+        _ => {}
+    }
+
+    parse_raw(spec)
+        .or_else(|| parse_relative(&lower, now))
+        .or_else(|| parse_iso8601(spec, now.offset_minutes()))
+        .or_else(|| parse_rfc2822(&lower))
+}
+
+// Days since the epoch of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn timestamp(date: (i64, i64, i64), time: (i64, i64, i64), offset: i32) -> Option<git2::Time> {
+    let (year, month, day) = date;
+    let (hour, minute, second) = time;
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || !(0..=23).contains(&hour)
+        || !(0..=59).contains(&minute)
+        || !(0..=60).contains(&second)
+    {
+        return None;
+    }
+
+    let local = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    Some(git2::Time::new(local - i64::from(offset) * 60, offset))
+}
+
+fn number(s: &str) -> Option<i64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+// A timezone like +0200, -07:00 or Z, in minutes east of UTC
+fn parse_offset(s: &str) -> Option<i32> {
+    if s == "Z" || s == "z" || s.eq_ignore_ascii_case("utc") || s.eq_ignore_ascii_case("gmt") {
+        return Some(0);
+    }
+
+    let sign = match s.as_bytes().first() {
+        Some(b'+') => 1,
+        Some(b'-') => -1,
+        _ => return None,
+    };
+    let digits: String = s[1..].chars().filter(|c| *c != ':').collect();
+    let (hours, minutes) = match digits.len() {
+        2 => (number(&digits)?, 0),
+        4 => (number(&digits[..2])?, number(&digits[2..])?),
+        _ => return None,
+    };
+    if hours > 14 || minutes > 59 {
+        return None;
+    }
+
+    Some(sign * (hours * 60 + minutes) as i32)
+}
+
+// HH:MM or HH:MM:SS
+fn parse_clock(s: &str) -> Option<(i64, i64, i64)> {
+    let parts: Vec<&str> = s.split(':').collect();
+    match parts.len() {
+        2 => Some((number(parts[0])?, number(parts[1])?, 0)),
+        3 => Some((number(parts[0])?, number(parts[1])?, number(parts[2])?)),
+        _ => None,
+    }
+}
+
+// git's internal format, "@1112911993" or "1112911993 +0200"
+fn parse_raw(spec: &str) -> Option<git2::Time> {
+    let mut parts = spec.split_whitespace();
+    let seconds = parts.next()?;
+    let offset = match parts.next() {
+        Some(tz) => parse_offset(tz)?,
+        None => 0,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let seconds = match seconds.strip_prefix('@') {
+        Some(s) => number(s)?,
+        // A bare number is only a timestamp if it cannot be a year
+        None if seconds.len() > 8 => number(seconds)?,
+        None => return None,
+    };
+
+    Some(git2::Time::new(seconds, offset))
+}
+
+// "3 days ago", "1.week.ago", "2 hours"
+fn parse_relative(spec: &str, now: git2::Time) -> Option<git2::Time> {
+    let words: Vec<&str> = spec
+        .split(|c: char| c.is_whitespace() || c == '.')
+        .filter(|w| !w.is_empty())
+        .collect();
+    let words = match words.last() {
+        Some(&"ago") => &words[..words.len() - 1],
+        _ => &words[..],
+    };
+    if words.len() != 2 {
+        return None;
+    }
+
+    let count = number(words[0])?;
+    let unit = words[1].trim_end_matches('s');
+    let seconds = match unit {
+        "second" | "sec" => 1,
+        "minute" | "min" => 60,
+        "hour" => 3600,
+        "day" => 86400,
+        "week" => 7 * 86400,
+        "month" => 30 * 86400,
+        "year" => 365 * 86400,
+        _ => return None,
+    };
+
+    Some(git2::Time::new(
+        now.seconds() - count * seconds,
+        now.offset_minutes(),
+    ))
+}
+
+// "2005-04-07", "2005-04-07 22:13", "2005-04-07T22:13:13Z",
+// "2005-04-07T22:13:13+02:00" or "2005-04-07 22:13:13 +0200"
+fn parse_iso8601(spec: &str, default_offset: i32) -> Option<git2::Time> {
+    if spec.len() < 10 || !spec.is_char_boundary(10) {
+        return None;
+    }
+    let (date, rest) = spec.split_at(10);
+    let fields: Vec<&str> = date.split('-').collect();
+    if fields.len() != 3 || fields[0].len() != 4 {
+        return None;
+    }
+    let date = (number(fields[0])?, number(fields[1])?, number(fields[2])?);
+
+    let rest = rest.trim_start_matches(['T', 't', ' ']);
+    if rest.is_empty() {
+        return timestamp(date, (0, 0, 0), default_offset);
+    }
+
+    // Split the clock from a trailing timezone
+    let tz_start = rest.find(['+', '-', 'Z', 'z', ' ']).unwrap_or(rest.len());
+    let (clock, tz) = rest.split_at(tz_start);
+    // Fractional seconds are allowed but ignored
+    let clock = clock.split('.').next()?;
+    let time = parse_clock(clock)?;
+    let offset = match tz.trim() {
+        "" => default_offset,
+        tz => parse_offset(tz)?,
+    };
+
+    timestamp(date, time, offset)
+}
+
+// "Thu, 07 Apr 2005 22:13:13 +0200", the day of the week is optional
+fn parse_rfc2822(spec: &str) -> Option<git2::Time> {
+    let spec = match spec.find(',') {
+        Some(comma) => &spec[comma + 1..],
+        None => spec,
+    };
+
+    let parts: Vec<&str> = spec.split_whitespace().collect();
+    if parts.len() != 5 {
+        return None;
+    }
+    let day = number(parts[0])?;
+    let month = MONTHS.iter().position(|m| parts[1].starts_with(m))? as i64 + 1;
+    let year = number(parts[2])?;
+    let time = parse_clock(parts[3])?;
+    let offset = parse_offset(parts[4])?;
+
+    timestamp((year, month, day), time, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2005-04-07 22:13:13 +0200
+    fn now() -> git2::Time {
+        git2::Time::new(1_112_904_793, 120)
+    }
+
+    fn at(spec: &str) -> Option<(i64, i32)> {
+        parse(spec, now()).map(|t| (t.seconds(), t.offset_minutes()))
+    }
+
+    #[test]
+    fn keywords() {
+        assert_eq!(at("now"), Some((1_112_904_793, 120)));
+        assert_eq!(at("yesterday"), Some((1_112_904_793 - 86400, 120)));
+    }
+
+    #[test]
+    fn relative() {
+        assert_eq!(at("3 days ago"), Some((1_112_904_793 - 3 * 86400, 120)));
+        assert_eq!(at("1.week.ago"), Some((1_112_904_793 - 7 * 86400, 120)));
+        assert_eq!(at("2 hours"), Some((1_112_904_793 - 7200, 120)));
+        assert_eq!(at("10 Minutes Ago"), Some((1_112_904_793 - 600, 120)));
+    }
+
+    #[test]
+    fn raw() {
+        assert_eq!(at("@1112911993"), Some((1_112_911_993, 0)));
+        assert_eq!(at("1112911993 +0200"), Some((1_112_911_993, 120)));
+    }
+
+    #[test]
+    fn iso8601() {
+        assert_eq!(at("2005-04-07T22:13:13Z"), Some((1_112_911_993, 0)));
+        assert_eq!(at("2005-04-07T22:13:13+02:00"), Some((1_112_904_793, 120)));
+        assert_eq!(at("2005-04-07 22:13:13 +0200"), Some((1_112_904_793, 120)));
+        assert_eq!(at("2005-04-07T22:13:13.250Z"), Some((1_112_911_993, 0)));
+        // Without a timezone the one of now is used
+        assert_eq!(at("2005-04-07 22:13"), Some((1_112_904_780, 120)));
+        assert_eq!(at("2005-04-07"), Some((1_112_824_800, 120)));
+    }
+
+    #[test]
+    fn rfc2822() {
+        assert_eq!(
+            at("Thu, 07 Apr 2005 22:13:13 +0200"),
+            Some((1_112_904_793, 120))
+        );
+        assert_eq!(at("7 Apr 2005 20:13:13 GMT"), Some((1_112_904_793, 0)));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(at(""), None);
+        assert_eq!(at("not a date"), None);
+        assert_eq!(at("2005-13-07"), None);
+        assert_eq!(at("2005-04-07T25:00:00Z"), None);
+        assert_eq!(at("3 fortnights ago"), None);
+        assert_eq!(at("1112911993 +9999"), None);
+        assert_eq!(at("Thu, 07 Foo 2005 22:13:13 +0200"), None);
+    }
+}
@@ -0,0 +1,5941 @@
+// The squash itself, the git-squash binary is a command line over it.
+
+extern crate encoding_rs;
+extern crate git2;
+#[macro_use]
+extern crate log;
+
+mod date;
+mod trailers;
+
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+// What is changed about a path in the index or working copy that keeps
+// the repository from being squashed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileState {
+    Conflicted,
+    Staged,
+    Deleted,
+    Modified,
+    Untracked,
+}
+
+impl fmt::Display for FileState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            FileState::Conflicted => "conflicted",
+            FileState::Staged => "staged",
+            FileState::Deleted => "deleted",
+            FileState::Modified => "modified",
+            FileState::Untracked => "untracked",
+        })
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum GitSquashError {
+    Git2(git2::Error),
+    DirtyRepo(Vec<(String, FileState)>),
+    SymbolicRef(String),
+    MessageNotInRange(String),
+    NoBase,
+    UnknownBase(String),
+    InvalidAuthor(String),
+    InvalidDate(String),
+    Gpg(String),
+    NoSuchDirectory(PathBuf),
+    PostSquash(String, bool),
+    WorkTreeWithoutGitDir,
+    NothingToSquash(usize),
+    CountTooLarge(usize, usize),
+    CheckedOutElsewhere(String, PathBuf),
+    Rewrite(String),
+    NoSuchPath(String),
+    DirtyAfterSquash,
+    Editor(String),
+    Todo(String),
+    Conflicts(String, Vec<String>),
+    BranchExists(String),
+    NoMergeBase(String, String),
+    BranchIsHead(String),
+    Push(String),
+    EmptyMessage(bool),
+    TooManyCommits(usize, usize),
+    Protected(String, String),
+    TreeChanged(git2::Oid, git2::Oid),
+    NotARepository(Option<PathBuf>),
+    NotSquashed(String),
+    MessageFile(PathBuf, String),
+    NotOnBranch(String, String),
+    NotFastForward(String),
+    BadSignature(git2::Oid, String),
+    ForeignAuthors(Vec<(String, usize)>),
+    InvalidRange(String),
+    NotAncestor(String, String),
+    RangeNotAtTip(String, String),
+    HookFailed(String),
+    NotALocalBranch(String),
+    PartialOnto(String),
+}
+
+impl GitSquashError {
+    // Scripts can tell a squash that had nothing to do from a failure
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            GitSquashError::NothingToSquash(_) => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl fmt::Display for GitSquashError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GitSquashError::Git2(ref e) => e.fmt(f),
+            GitSquashError::DirtyRepo(ref files) => {
+                if files.iter().any(|&(_, state)| state == FileState::Conflicted) {
+                    write!(
+                        f,
+                        "The repo has unresolved conflicts, please resolve them and commit, \
+                         or reset, before squashing"
+                    )?;
+                } else {
+                    write!(f, "The repo is dirty, please stash or commit changes")?;
+                }
+                for &(ref path, state) in files.iter().take(10) {
+                    write!(f, "\n    {:<12}{}", format!("{}:", state), path)?;
+                }
+                if files.len() > 10 {
+                    write!(f, "\n    ...and {} more", files.len() - 10)?;
+                }
+                Ok(())
+            }
+            GitSquashError::SymbolicRef(ref r) => {
+                write!(f, "{} is a symbolic reference cannot be used for squash", r)
+            }
+            GitSquashError::MessageNotInRange(ref r) => {
+                write!(f, "{} is not one of the commits being squashed", r)
+            }
+            GitSquashError::NoBase => write!(
+                f,
+                "Could not determine the branch to squash on to, please pass it explicitly"
+            ),
+            GitSquashError::UnknownBase(ref b) => {
+                write!(f, "{} is not a branch, tag or commit", b)
+            }
+            GitSquashError::InvalidAuthor(ref a) => {
+                write!(f, "{} is not in the form 'Name <email>'", a)
+            }
+            GitSquashError::InvalidDate(ref d) => write!(
+                f,
+                "{} is not a valid date, use RFC 2822, ISO 8601 or a relative date like '2 days ago'",
+                d
+            ),
+            GitSquashError::Gpg(ref e) => write!(f, "gpg failed to sign the data: {}", e),
+            GitSquashError::NoSuchDirectory(ref p) => {
+                write!(f, "cannot change to '{}': No such directory", p.display())
+            }
+            GitSquashError::WorkTreeWithoutGitDir => {
+                write!(f, "GIT_WORK_TREE is set but GIT_DIR is not, set both or neither")
+            }
+            GitSquashError::NothingToSquash(0) => write!(f, "No commits to squash"),
+            GitSquashError::NothingToSquash(_) => write!(f, "Only one commit to squash"),
+            GitSquashError::CountTooLarge(count, len) => write!(
+                f,
+                "Cannot squash the last {} commits, the branch only has {} commits on top of the base",
+                count, len
+            ),
+            GitSquashError::CheckedOutElsewhere(ref b, ref p) => write!(
+                f,
+                "{} is checked out in the worktree at {}, squash it from there or pass --force",
+                b,
+                p.display()
+            ),
+            GitSquashError::Rewrite(ref e) => write!(f, "Cannot rewrite the branch: {}", e),
+            GitSquashError::NoSuchPath(ref p) => {
+                write!(f, "{} does not exist on the branch or the base", p)
+            }
+            GitSquashError::DirtyAfterSquash => write!(
+                f,
+                "The repo is dirty after squashing, the index or working copy do not match the squashed commit"
+            ),
+            GitSquashError::Editor(ref e) => write!(f, "There was a problem with the editor '{}'", e),
+            GitSquashError::Todo(ref e) => write!(f, "Invalid todo list: {}", e),
+            GitSquashError::Conflicts(ref b, ref paths) => write!(
+                f,
+                "The squashed changes conflict with {} in:\n    {}\nrebase the branch manually instead",
+                b,
+                paths.join("\n    ")
+            ),
+            GitSquashError::BranchExists(ref b) => {
+                write!(f, "A branch named '{}' already exists, pass --force to replace it", b)
+            }
+            GitSquashError::NoMergeBase(ref b, ref base) => {
+                write!(f, "{} and {} have no history in common, there is nothing to squash onto", b, base)
+            }
+            GitSquashError::BranchIsHead(ref b) => {
+                write!(f, "{} is checked out, squash it without --branch", b)
+            }
+            GitSquashError::Push(ref e) => write!(f, "The squash was kept but pushing failed: {}", e),
+            GitSquashError::EmptyMessage(false) => {
+                write!(f, "Aborting the squash due to an empty message")
+            }
+            GitSquashError::EmptyMessage(true) => write!(
+                f,
+                "Aborting the squash, the message is empty once its comments and whitespace are stripped"
+            ),
+            GitSquashError::TooManyCommits(count, limit) => write!(
+                f,
+                "Refusing to squash {} commits, more than the limit of {}. Check the base or raise --max-commits",
+                count, limit
+            ),
+            GitSquashError::Protected(ref branch, ref pattern) => write!(
+                f,
+                "{} is a protected branch (matches '{}') and cannot be squashed, pass \
+                 --no-protect to squash it anyway",
+                branch, pattern
+            ),
+            GitSquashError::TreeChanged(expected, actual) => write!(
+                f,
+                "The squashed commit has tree {} instead of {}, the tree of the branch. \
+                 Nothing was changed, please report this",
+                actual, expected
+            ),
+            GitSquashError::NotARepository(None) => {
+                write!(f, "not a git repository (or any parent up to mount point)")
+            }
+            GitSquashError::NotARepository(Some(ref dir)) => {
+                write!(f, "not a git repository: '{}'", dir.display())
+            }
+            GitSquashError::NotSquashed(ref b) => write!(
+                f,
+                "The last change to {} was not a squash, there is nothing to undo",
+                b
+            ),
+            GitSquashError::MessageFile(ref path, ref e) => {
+                write!(f, "Cannot read the message from {}: {}", path.display(), e)
+            }
+            GitSquashError::NotOnBranch(ref r, ref b) => write!(
+                f,
+                "{} is not one of the commits of {} after its base",
+                r, b
+            ),
+            GitSquashError::NotFastForward(ref b) => write!(
+                f,
+                "Cannot advance {} to the squashed commit, it is not a fast-forward. Pass --force to move it anyway",
+                b
+            ),
+            GitSquashError::BadSignature(oid, ref reason) => write!(
+                f,
+                "{} does not have a good signature by a trusted key: {}",
+                short_id(oid),
+                reason
+            ),
+            GitSquashError::ForeignAuthors(ref authors) => {
+                write!(
+                    f,
+                    "Some of the squashed commits are by other authors, whose authorship would be \
+                     lost. Pass --allow-foreign-authors to squash them anyway"
+                )?;
+                for &(ref author, count) in authors {
+                    let commits = if count == 1 { "commit" } else { "commits" };
+                    write!(f, "\n    {} ({} {})", author, count, commits)?;
+                }
+                Ok(())
+            }
+            GitSquashError::InvalidRange(ref r) => {
+                write!(f, "{} is not a range of commits like <base>..<tip>", r)
+            }
+            GitSquashError::NotAncestor(ref a, ref b) => {
+                write!(f, "{} is not an ancestor of {}, there is no range to squash", a, b)
+            }
+            GitSquashError::RangeNotAtTip(ref r, ref b) => write!(
+                f,
+                "The range {} does not end at the tip of {}, pass --new-branch to squash it \
+                 into a new branch",
+                r, b
+            ),
+            GitSquashError::HookFailed(ref h) => write!(
+                f,
+                "The {} hook rejected the squashed commit, pass --no-verify to bypass it",
+                h
+            ),
+            GitSquashError::NotALocalBranch(ref b) => write!(f, "{} is not a local branch", b),
+            GitSquashError::PartialOnto(ref base) => write!(
+                f,
+                "--onto moves the whole branch onto the tip of {}, it cannot be combined with \
+                 --count, --since, --into or --split-at",
+                base
+            ),
+            GitSquashError::PostSquash(ref c, rolled_back) => write!(
+                f,
+                "post-squash command `{}` failed, {}",
+                c,
+                if rolled_back {
+                    "the squash was rolled back"
+                } else {
+                    "the squash was kept"
+                }
+            ),
+        }
+    }
+}
+
+impl error::Error for GitSquashError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            GitSquashError::Git2(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// Errors are returned to tools that embed the squash, which may move them
+// between threads
+const _: fn() = || {
+    fn send_sync<T: Send + Sync + 'static>() {}
+    send_sync::<GitSquashError>();
+};
+
+impl From<git2::Error> for GitSquashError {
+    fn from(err: git2::Error) -> GitSquashError {
+        GitSquashError::Git2(err)
+    }
+}
+
+// Which of the squashed commits supplies the message of the new commit
+#[derive(Default)]
+pub enum MessageSource {
+    #[default]
+    Oldest,
+    Newest,
+    Rev(String),
+}
+
+impl MessageSource {
+    pub fn parse(value: &str) -> MessageSource {
+        match value {
+            "oldest" => MessageSource::Oldest,
+            "newest" => MessageSource::Newest,
+            rev => MessageSource::Rev(rev.to_string()),
+        }
+    }
+}
+
+// How the message of the squashed commit is cleaned up, like git commit
+// --cleanup
+#[derive(Default, Clone, Copy, PartialEq)]
+pub enum Cleanup {
+    // strip when the message is edited, otherwise it is used as it is
+    #[default]
+    Default,
+    Strip,
+    Whitespace,
+    Scissors,
+    Verbatim,
+}
+
+impl Cleanup {
+    pub fn parse(value: &str) -> Option<Cleanup> {
+        match value {
+            "default" => Some(Cleanup::Default),
+            "strip" => Some(Cleanup::Strip),
+            "whitespace" => Some(Cleanup::Whitespace),
+            "scissors" => Some(Cleanup::Scissors),
+            "verbatim" => Some(Cleanup::Verbatim),
+            _ => None,
+        }
+    }
+}
+
+// --cleanup, or else commit.cleanup
+fn cleanup_mode(repo: &git2::Repository, opts: &SquashOptions) -> Cleanup {
+    opts.cleanup.unwrap_or_else(|| {
+        repo.config()
+            .and_then(|c| c.get_string("commit.cleanup"))
+            .ok()
+            .and_then(|value| Cleanup::parse(&value))
+            .unwrap_or_default()
+    })
+}
+
+// The scissors line, after the comment character
+const SCISSORS: &str = "------------------------ >8 ------------------------";
+
+// The character comment lines start with, core.commentChar or '#'. With
+// auto it is the first of git's candidates no line of the message starts
+// with.
+fn comment_char(config: &git2::Config, message: &str) -> char {
+    match config.get_string("core.commentChar") {
+        Ok(ref value) if value == "auto" => "#;@!$%^&|:"
+            .chars()
+            .find(|c| !message.lines().any(|l| l.starts_with(*c)))
+            .unwrap_or('#'),
+        Ok(value) => value.chars().next().unwrap_or('#'),
+        Err(_) => '#',
+    }
+}
+
+// Comment out each line of a text
+fn commented(text: &str, comment: char) -> String {
+    let mut lines = String::new();
+    for line in text.lines() {
+        lines.push(comment);
+        if !line.is_empty() {
+            lines.push(' ');
+            lines.push_str(line);
+        }
+        lines.push('\n');
+    }
+    lines
+}
+
+// The message above its scissors line
+fn cut_at_scissors(message: &str, comment: char) -> &str {
+    let scissors = format!("{} {}", comment, SCISSORS);
+    match message.lines().position(|l| l == scissors) {
+        Some(start) => {
+            let end: usize = message.lines().take(start).map(|l| l.len() + 1).sum();
+            &message[..end.min(message.len())]
+        }
+        None => message,
+    }
+}
+
+// Clean up a message the way git commit does. Scissors only cut an edited
+// message, the text below them was added for the editor.
+fn cleanup_message(message: &str, cleanup: Cleanup, edited: bool, comment: char) -> String {
+    let mut message = message;
+    if cleanup == Cleanup::Verbatim {
+        return message.to_string();
+    }
+    if cleanup == Cleanup::Scissors && edited {
+        message = cut_at_scissors(message, comment);
+    }
+
+    // Trailing whitespace goes, runs of blank lines become one and leading
+    // and trailing blank lines are dropped
+    let strip = cleanup == Cleanup::Strip || (cleanup == Cleanup::Default && edited);
+    let mut lines: Vec<&str> = Vec::new();
+    for line in message.lines() {
+        if strip && line.starts_with(comment) {
+            continue;
+        }
+        let line = line.trim_end();
+        if line.is_empty() && lines.last().map_or(true, |l| l.is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last() == Some(&"") {
+        lines.pop();
+    }
+
+    if lines.is_empty() {
+        String::new()
+    } else {
+        lines.join("\n") + "\n"
+    }
+}
+
+// Where the base of the squash came from
+enum BaseSource {
+    Argument,
+    Config(String),
+    Upstream(String),
+    OriginHead,
+    DefaultBranch,
+}
+
+impl fmt::Display for BaseSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BaseSource::Argument => write!(f, "given on the command line"),
+            BaseSource::Config(ref key) => write!(f, "from {} in git config", key),
+            BaseSource::Upstream(ref b) => write!(f, "the upstream of {}", b),
+            BaseSource::OriginHead => write!(f, "the default branch of origin"),
+            BaseSource::DefaultBranch => write!(f, "detected default branch"),
+        }
+    }
+}
+
+// The resolved base of the squash
+struct Base {
+    name: String,
+    oid: git2::Oid,
+    source: BaseSource,
+}
+
+// How a message from a squash is meant, the command line prints Info on
+// stdout and the others on stderr, with a prefix like git's for notes,
+// hints, warnings and errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    Status,
+    Note,
+    Hint,
+    Warning,
+    Error,
+}
+
+// Where a squash sends what it has to tell, and how it asks before doing
+// something the user may not want. The library itself never writes to the
+// terminal or reads a prompt from stdin.
+pub trait Reporter {
+    fn report(&self, level: Level, message: &str);
+
+    // Without anyone to answer, the answer is no
+    fn confirm(&self, _question: &str) -> bool {
+        false
+    }
+
+    // A status line for the slow parts, replaced by the next one and
+    // cleared with None
+    fn progress(&self, _status: Option<&str>) {}
+}
+
+// What to squash and how, everything the command line can ask for
+#[derive(Default)]
+pub struct SquashOptions {
+    pub dir: PathBuf,
+    pub git_dir: Option<PathBuf>,
+    pub base: Option<String>,
+    pub branch: Option<String>,
+    pub message_from: MessageSource,
+    pub message_file: Option<PathBuf>,
+    pub reuse_message: Option<String>,
+    pub count: Option<usize>,
+    pub into: Option<String>,
+    pub split_at: Option<String>,
+    pub range: Option<String>,
+    pub max_commits: Option<usize>,
+    pub first_parent: bool,
+    pub since: Option<String>,
+    pub allow_unsigned_squash: bool,
+    pub verify_signatures: bool,
+    pub author: Option<String>,
+    pub keep_author: bool,
+    pub allow_foreign_authors: bool,
+    pub committer_date_is_author_date: bool,
+    pub protect: Vec<String>,
+    pub no_protect: bool,
+    pub reflog_message: Option<String>,
+    pub date: Option<String>,
+    pub gpg_sign: Option<bool>,
+    pub edit: Option<bool>,
+    pub cleanup: Option<Cleanup>,
+    pub collect_trailers: bool,
+    pub record_squashed: bool,
+    pub no_verify: bool,
+    pub no_backup: bool,
+    pub allow_empty_message: bool,
+    pub gpg_key: Option<String>,
+    pub gpg_program: Option<String>,
+    pub resign: bool,
+    pub post_squash: Option<String>,
+    pub post_squash_rollback: bool,
+    pub require_squash: bool,
+    pub verify_clean_after: bool,
+    pub no_verify_tree: bool,
+    pub copy_notes: bool,
+    pub notes_ref: Option<String>,
+    pub strict: bool,
+    pub allow_dirty: bool,
+    pub wip: bool,
+    pub wip_untracked: bool,
+    pub skip_dirty_check: bool,
+    pub ignore_submodules: Option<String>,
+    pub autostash: Option<bool>,
+    pub reuse_existing: bool,
+    pub idempotent: bool,
+    pub rewrite_single: bool,
+    pub autosquash: bool,
+    pub interactive: bool,
+    pub onto_base: bool,
+    pub new_branch: Option<String>,
+    pub force: bool,
+    pub advance: Option<String>,
+    pub push: bool,
+    pub remote: Option<String>,
+    pub path: Option<String>,
+    pub dry_run: bool,
+    pub preview: bool,
+    pub print_base: bool,
+    pub stat_only: bool,
+    pub verbose: bool,
+    pub quiet: bool,
+    pub quiet_if_noop: bool,
+    pub abbrev: Option<usize>,
+    // Nothing is reported without one
+    pub reporter: Option<Box<dyn Reporter>>,
+}
+
+impl SquashOptions {
+    fn report(&self, level: Level, message: &str) {
+        if let Some(ref reporter) = self.reporter {
+            reporter.report(level, message);
+        }
+    }
+}
+
+pub enum OutcomeKind {
+    // --print-base only looked at the merge base
+    Base,
+    NoCommits,
+    // The branch has no commits of its own, its tip is in the named base,
+    // this many commits behind it
+    Contained(String, usize),
+    SingleCommit,
+    DryRun,
+    // --autosquash or --interactive folded the listed commits, or would in
+    // a dry run
+    Folded,
+    // --reuse-existing or --idempotent found a commit on the branch that
+    // already is the squash
+    AlreadySquashed(git2::Oid),
+    // --preview stopped with the message the squashed commit would get
+    Preview(Vec<u8>),
+    Squashed,
+}
+
+pub struct SquashedCommit {
+    pub id: git2::Oid,
+    pub summary: String,
+}
+
+// The result of a squash, or what a dry run would have done
+pub struct SquashOutcome {
+    pub kind: OutcomeKind,
+    pub branch: String,
+    // The tip of the branch before the squash
+    pub head: git2::Oid,
+    pub merge_base: git2::Oid,
+    // The commit the squashed commit is created on, the merge base unless
+    // only part of the branch is squashed
+    pub onto: git2::Oid,
+    pub commits: Vec<SquashedCommit>,
+    // The new tip of the branch once it has been rewritten
+    pub tip: Option<git2::Oid>,
+    // The ref keeping the tip from before the squash
+    pub backup: Option<String>,
+    // Whether --wip put changes from the working copy in the squashed commit
+    pub wip: bool,
+}
+
+// The author or committer like git picks them, GIT_AUTHOR_NAME,
+// GIT_AUTHOR_EMAIL and GIT_AUTHOR_DATE or the GIT_COMMITTER_ ones override
+// user.name, user.email and the current time.
+fn identity(
+    repo: &git2::Repository,
+    role: &str,
+) -> Result<git2::Signature<'static>, GitSquashError> {
+    let var = |field: &str| env::var(format!("GIT_{}_{}", role, field)).ok();
+    let (name, email, date) = (var("NAME"), var("EMAIL"), var("DATE"));
+    if name.is_none() && email.is_none() && date.is_none() {
+        return Ok(repo.signature()?.to_owned());
+    }
+
+    let config = repo.config()?;
+    let name = match name {
+        Some(name) => name,
+        None => config.get_string("user.name")?,
+    };
+    let email = match email {
+        Some(email) => email,
+        None => config.get_string("user.email")?,
+    };
+    let now = git2::Signature::now(&name, &email)?;
+    let when = match date {
+        Some(ref spec) => date::parse(spec, now.when())
+            .ok_or_else(|| GitSquashError::InvalidDate(spec.to_string()))?,
+        None => now.when(),
+    };
+    Ok(git2::Signature::new(&name, &email, &when)?)
+}
+
+// The same identity at another time
+fn retimed(
+    sig: &git2::Signature,
+    when: &git2::Time,
+) -> Result<git2::Signature<'static>, git2::Error> {
+    git2::Signature::new(
+        &String::from_utf8_lossy(sig.name_bytes()),
+        &String::from_utf8_lossy(sig.email_bytes()),
+        when,
+    )
+}
+
+// Parse an identity like git commit --author, "Name <email>"
+fn parse_author(ident: &str) -> Result<git2::Signature<'static>, GitSquashError> {
+    let invalid = || GitSquashError::InvalidAuthor(ident.to_string());
+
+    let ident = ident.trim();
+    if !ident.ends_with('>') {
+        return Err(invalid());
+    }
+    let open = ident.find('<').ok_or_else(invalid)?;
+    let name = ident[..open].trim();
+    let email = &ident[open + 1..ident.len() - 1];
+    if name.is_empty() || email.is_empty() || email.contains('<') || email.contains('>') {
+        return Err(invalid());
+    }
+
+    git2::Signature::now(name, email).map_err(|_| invalid())
+}
+
+// The status line for the slow parts of a squash in a big repository,
+// not with --quiet. It is cleared before anything else is reported.
+struct Progress<'a> {
+    reporter: Option<&'a dyn Reporter>,
+    shown: bool,
+}
+
+impl<'a> Progress<'a> {
+    fn new(opts: &'a SquashOptions) -> Progress<'a> {
+        Progress {
+            reporter: opts.reporter.as_deref().filter(|_| !opts.quiet),
+            shown: false,
+        }
+    }
+
+    fn update(&mut self, status: &str) {
+        if let Some(reporter) = self.reporter {
+            reporter.progress(Some(status));
+            self.shown = true;
+        }
+    }
+
+    fn clear(&mut self) {
+        if let (Some(reporter), true) = (self.reporter, self.shown) {
+            reporter.progress(None);
+            self.shown = false;
+        }
+    }
+}
+
+// The OpenPGP program to sign and check signatures with, --gpg-program or
+// else gpg.openpgp.program or gpg.program like git, and gpg from the PATH
+// without any. A relative path is relative to the directory the command
+// runs in. The environment is passed on, so GNUPGHOME picks the keyring.
+fn gpg_program(opts: &SquashOptions, config: &git2::Config) -> String {
+    match opts.gpg_program {
+        Some(ref program) if program.contains('/') => {
+            opts.dir.join(program).to_string_lossy().into_owned()
+        }
+        Some(ref program) => program.to_string(),
+        None => config
+            .get_string("gpg.openpgp.program")
+            .or_else(|_| config.get_string("gpg.program"))
+            .unwrap_or_else(|_| "gpg".to_string()),
+    }
+}
+
+// How to sign the squashed commit
+struct Signing {
+    program: String,
+    key: String,
+}
+
+impl Signing {
+    // Decide whether to sign from the options, squash.gpgSign and then
+    // commit.gpgsign, picking the key and program the same way git commit
+    // does. With --resign, resigned says whether any of the squashed
+    // commits were signed. The config is the layered one from the
+    // repository, so [include] and [includeIf] files are honored.
+    fn from_config(
+        opts: &SquashOptions,
+        config: &git2::Config,
+        committer: &git2::Signature,
+        resigned: bool,
+    ) -> Option<Signing> {
+        let enabled = opts.gpg_sign.unwrap_or_else(|| {
+            resigned
+                || config
+                    .get_bool("squash.gpgSign")
+                    .or_else(|_| config.get_bool("commit.gpgsign"))
+                    .unwrap_or(false)
+        });
+        if !enabled {
+            return None;
+        }
+
+        let key = match opts.gpg_key {
+            Some(ref key) => key.to_string(),
+            None => config.get_string("user.signingkey").unwrap_or_else(|_| {
+                format!(
+                    "{} <{}>",
+                    String::from_utf8_lossy(committer.name_bytes()),
+                    String::from_utf8_lossy(committer.email_bytes())
+                )
+            }),
+        };
+        let program = gpg_program(opts, config);
+
+        Some(Signing { program, key })
+    }
+
+    // Produce a detached armored signature for the commit buffer
+    fn sign(&self, content: &str) -> Result<String, GitSquashError> {
+        let mut child = process::Command::new(&self.program)
+            .args(["--status-fd=2", "-bsau", &self.key])
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .spawn()
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => GitSquashError::Gpg(format!(
+                    "cannot find {}, set gpg.program or pass --gpg-program",
+                    self.program
+                )),
+                _ => GitSquashError::Gpg(format!("cannot run {}: {}", self.program, e)),
+            })?;
+
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(content.as_bytes())
+            .map_err(|e| GitSquashError::Gpg(e.to_string()))?;
+        let output = child
+            .wait_with_output()
+            .map_err(|e| GitSquashError::Gpg(e.to_string()))?;
+
+        let status = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() || !status.contains("[GNUPG:] SIG_CREATED ") {
+            let messages: Vec<&str> = status
+                .lines()
+                .filter(|l| !l.starts_with("[GNUPG:]"))
+                .collect();
+            if messages.is_empty() {
+                return Err(GitSquashError::Gpg(format!(
+                    "{} exited without a signature, {}",
+                    self.program, output.status
+                )));
+            }
+            return Err(GitSquashError::Gpg(messages.join("\n")));
+        }
+
+        String::from_utf8(output.stdout).map_err(|e| GitSquashError::Gpg(e.to_string()))
+    }
+}
+
+// Write a commit object without updating any reference, signing it if
+// requested. The message is written as the raw bytes it was read as and
+// the encoding header is carried over, so messages in other encodings are
+// still rendered correctly.
+#[allow(clippy::too_many_arguments)]
+fn create_commit(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+    author: &git2::Signature,
+    committer: &git2::Signature,
+    message: &[u8],
+    encoding: Option<&str>,
+    tree: &git2::Tree,
+    parents: &[&git2::Commit],
+    signing: Option<&Signing>,
+) -> Result<git2::Oid, GitSquashError> {
+    // The buffer is only headers and the blank line that ends them, the
+    // message is appended as is
+    let buffer = repo.commit_create_buffer(author, committer, "", tree, parents)?;
+    let mut content = buffer.to_vec();
+    if let Some(encoding) = encoding {
+        // The encoding header goes last like git writes it
+        content.pop();
+        content.extend_from_slice(format!("encoding {}\n\n", encoding).as_bytes());
+    }
+    content.extend_from_slice(message);
+
+    match signing {
+        None => Ok(repo.odb()?.write(git2::ObjectType::Commit, &content)?),
+        Some(signing) => {
+            // Signing works on text, a message that is not UTF-8 is
+            // converted rather than failing
+            let content = match String::from_utf8(content) {
+                Ok(content) => content,
+                Err(e) => {
+                    opts.report(
+                        Level::Warning,
+                        "the commit message is not UTF-8, signing a converted copy",
+                    );
+                    String::from_utf8_lossy(e.as_bytes()).into_owned()
+                }
+            };
+            let signature = signing.sign(&content)?;
+            Ok(repo.commit_signed(&content, &signature, None)?)
+        }
+    }
+}
+
+fn message_encoding(commit: &git2::Commit) -> &'static encoding_rs::Encoding {
+    commit
+        .message_encoding()
+        .and_then(|label| encoding_rs::Encoding::for_label(label.as_bytes()))
+        .unwrap_or(encoding_rs::UTF_8)
+}
+
+// The message of a commit as text, decoded from the encoding in its
+// header. Bytes that are not valid in it are replaced with a warning.
+fn decode_message(opts: &SquashOptions, commit: &git2::Commit) -> String {
+    let encoding = message_encoding(commit);
+    let (text, errors) = encoding.decode_without_bom_handling(commit.message_bytes());
+    if errors {
+        opts.report(
+            Level::Warning,
+            &format!(
+                "the message of {} is not valid {}, some characters were replaced",
+                short_id(commit.id()),
+                encoding.name()
+            ),
+        );
+    }
+    text.into_owned()
+}
+
+// Encode a message for a new commit in the encoding with the given label,
+// returning the bytes and the encoding header to write. Without a label,
+// with an unknown one or when the text cannot be represented in it the
+// message is written as UTF-8, which needs no header.
+fn encode_message(
+    opts: &SquashOptions,
+    text: &str,
+    label: Option<&str>,
+) -> (Vec<u8>, Option<String>) {
+    let label = match label {
+        Some(label) => label,
+        None => return (text.as_bytes().to_vec(), None),
+    };
+    match encoding_rs::Encoding::for_label(label.as_bytes()) {
+        Some(encoding) if encoding == encoding_rs::UTF_8 => (text.as_bytes().to_vec(), None),
+        Some(encoding) => {
+            let (bytes, _, errors) = encoding.encode(text);
+            if errors {
+                opts.report(
+                    Level::Warning,
+                    &format!(
+                        "the message cannot be written in {}, writing it as UTF-8",
+                        label
+                    ),
+                );
+                return (text.as_bytes().to_vec(), None);
+            }
+            (bytes.into_owned(), Some(label.to_string()))
+        }
+        None => {
+            opts.report(
+                Level::Warning,
+                &format!("unknown encoding {}, writing the message as UTF-8", label),
+            );
+            (text.as_bytes().to_vec(), None)
+        }
+    }
+}
+
+// The subject of a commit as text, for listings and matching fixup!
+// subjects, which do not need the exact bytes
+fn summary(commit: &git2::Commit) -> String {
+    let (text, _) =
+        message_encoding(commit).decode_without_bom_handling(commit.summary_bytes().unwrap_or(b""));
+    text.into_owned()
+}
+
+// Point HEAD, or the branch it is on, at a new commit
+fn update_head(
+    repo: &git2::Repository,
+    oid: git2::Oid,
+    log_message: &str,
+) -> Result<(), git2::Error> {
+    let head = repo.find_reference("HEAD")?;
+    match head.symbolic_target() {
+        Some(refname) => repo.reference(refname, oid, true, log_message).map(|_| ()),
+        None => repo.set_head_detached(oid),
+    }
+}
+
+fn squashed_commits(
+    repo: &git2::Repository,
+    commits: &[git2::Oid],
+) -> Result<Vec<SquashedCommit>, git2::Error> {
+    let mut squashed = Vec::new();
+    for oid in commits {
+        let commit = repo.find_commit(*oid)?;
+        squashed.push(SquashedCommit {
+            id: *oid,
+            summary: summary(&commit),
+        });
+    }
+    Ok(squashed)
+}
+
+// The message in a --file, or on stdin for -. A relative path is taken
+// from the directory the squash runs in.
+fn read_message_file(dir: &Path, path: &Path) -> Result<String, GitSquashError> {
+    let mut bytes = Vec::new();
+    let read = if path == Path::new("-") {
+        io::stdin().read_to_end(&mut bytes).map(|_| ())
+    } else {
+        fs::read(dir.join(path)).map(|b| bytes = b)
+    };
+    read.map_err(|e| GitSquashError::MessageFile(path.to_path_buf(), e.to_string()))?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// The message with the trailers of every squashed commit, oldest first,
+// merged into its own, for --collect-trailers
+fn collect_trailers(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+    commits: &[git2::Oid],
+    message: &str,
+) -> Result<String, git2::Error> {
+    let (text, mut collected) = trailers::parse(message);
+    for oid in commits.iter().rev() {
+        let commit = repo.find_commit(*oid)?;
+        collected.extend(trailers::parse(&decode_message(opts, &commit)).1);
+    }
+    Ok(trailers::format(text, &trailers::merge(collected)))
+}
+
+// A Squashed-commit trailer with the full id of each squashed commit, oldest
+// first, added to the trailers the message already ends with
+fn record_squashed(message: &str, commits: &[git2::Oid]) -> String {
+    let (text, mut trailers) = trailers::parse(message);
+    for oid in commits.iter().rev() {
+        trailers.push(trailers::Trailer {
+            key: "Squashed-commit".to_string(),
+            value: oid.to_string(),
+        });
+    }
+    trailers::format(text, &trailers)
+}
+
+// The first commit of the branch, the one on the first-parent chain of the
+// tip whose parent is where the squashed commit goes. Without one, like
+// when onto is only reached through a merge, fall back to the last commit
+// of the walk, which is sorted newest first.
+fn oldest_commit(
+    repo: &git2::Repository,
+    commits: &[git2::Oid],
+    head: git2::Oid,
+    onto: git2::Oid,
+) -> Result<git2::Oid, git2::Error> {
+    let mut oid = head;
+    while commits.contains(&oid) {
+        let commit = repo.find_commit(oid)?;
+        match commit.parent_id(0) {
+            Ok(parent) if parent == onto => return Ok(oid),
+            Ok(parent) => oid = parent,
+            Err(_) => break,
+        }
+    }
+
+    Ok(*commits.last().unwrap())
+}
+
+pub fn short_id(oid: git2::Oid) -> String {
+    oid.to_string()[..7].to_string()
+}
+
+// Abbreviate an id for output to --abbrev or else core.abbrev digits,
+// taking more when that prefix is ambiguous in the repository like git
+// does.
+pub fn abbrev_id(repo: &git2::Repository, oid: git2::Oid, abbrev: Option<usize>) -> String {
+    let hex = oid.to_string();
+    let len = match abbrev {
+        Some(len) => len,
+        None => match repo.config().and_then(|c| c.get_string("core.abbrev")) {
+            Ok(ref value) if value == "no" => hex.len(),
+            Ok(value) => value.parse().unwrap_or(7),
+            Err(_) => 7,
+        },
+    };
+
+    let mut len = len.clamp(4, hex.len());
+    if let Ok(odb) = repo.odb() {
+        while len < hex.len() {
+            let prefix = git2::Oid::from_str(&hex[..len]);
+            match prefix.and_then(|p| odb.exists_prefix(p, len)) {
+                Err(ref e) if e.code() == git2::ErrorCode::Ambiguous => len += 1,
+                _ => break,
+            }
+        }
+    }
+    hex[..len].to_string()
+}
+
+// Apply each -C in turn like git does, relative paths are relative to the
+// directory of the preceding -C.
+pub fn start_dir<'a, I: Iterator<Item = &'a str>>(dirs: I) -> Result<PathBuf, GitSquashError> {
+    let mut dir = PathBuf::from(".");
+    for d in dirs {
+        if d.is_empty() {
+            continue;
+        }
+        dir = dir.join(d);
+        if !dir.is_dir() {
+            return Err(GitSquashError::NoSuchDirectory(Path::new(d).to_path_buf()));
+        }
+    }
+
+    Ok(dir)
+}
+
+// Locate the repository the way git does. An explicit --git-dir or GIT_DIR
+// is opened as is, otherwise the repository is discovered upwards from the
+// starting directory without crossing GIT_CEILING_DIRECTORIES.
+pub fn open_repo(opts: &SquashOptions) -> Result<git2::Repository, GitSquashError> {
+    let git_dir = opts
+        .git_dir
+        .clone()
+        .or_else(|| env::var_os("GIT_DIR").map(PathBuf::from));
+    let work_tree = env::var_os("GIT_WORK_TREE").map(PathBuf::from);
+
+    let git_dir = match git_dir {
+        Some(git_dir) => opts.dir.join(git_dir),
+        None => {
+            // git only honors GIT_WORK_TREE together with GIT_DIR
+            if work_tree.is_some() {
+                return Err(GitSquashError::WorkTreeWithoutGitDir);
+            }
+
+            let ceilings: Vec<PathBuf> = env::var_os("GIT_CEILING_DIRECTORIES")
+                .map(|dirs| env::split_paths(&dirs).collect())
+                .unwrap_or_default();
+            return git2::Repository::open_ext(
+                &opts.dir,
+                git2::RepositoryOpenFlags::empty(),
+                &ceilings,
+            )
+            .map_err(|e| match e.code() {
+                git2::ErrorCode::NotFound => GitSquashError::NotARepository(None),
+                _ => e.into(),
+            });
+        }
+    };
+
+    let repo = git2::Repository::open_ext(
+        &git_dir,
+        git2::RepositoryOpenFlags::NO_SEARCH,
+        &[] as &[&std::ffi::OsStr],
+    )
+    .map_err(|e| match e.code() {
+        git2::ErrorCode::NotFound => GitSquashError::NotARepository(Some(git_dir.clone())),
+        _ => e.into(),
+    })?;
+
+    // The working tree comes from GIT_WORK_TREE, then core.worktree which is
+    // relative to the repository, and otherwise is the current directory.
+    let work_tree = match work_tree {
+        Some(work_tree) => Some(opts.dir.join(work_tree)),
+        None => match repo.config()?.get_path("core.worktree") {
+            Ok(path) => Some(git_dir.join(path)),
+            Err(_) if repo.is_bare() => None,
+            Err(_) => Some(opts.dir.clone()),
+        },
+    };
+    if let Some(work_tree) = work_tree {
+        let work_tree = work_tree
+            .canonicalize()
+            .map_err(|_| GitSquashError::NoSuchDirectory(work_tree))?;
+        repo.set_workdir(&work_tree, false)?;
+    }
+
+    Ok(repo)
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+// The directory shared by all worktrees of a repository
+fn common_dir(repo: &git2::Repository) -> PathBuf {
+    if repo.is_worktree() {
+        if let Ok(commondir) = fs::read_to_string(repo.path().join("commondir")) {
+            let common = repo.path().join(commondir.trim());
+            return common.canonicalize().unwrap_or(common);
+        }
+    }
+    repo.path().to_path_buf()
+}
+
+// The commit-graph git wrote for the repository, shared by all worktrees
+#[derive(Debug, PartialEq)]
+enum CommitGraph {
+    Missing,
+    // A single file, which libgit2 reads when it opens the object database
+    File(PathBuf),
+    // A single file with chunks libgit2 does not know, like the generation
+    // data git writes by default, which it ignores as a whole
+    Unsupported(PathBuf),
+    // Only a chain of split files, which libgit2 cannot read
+    Chain,
+}
+
+fn commit_graph(repo: &git2::Repository) -> CommitGraph {
+    let info = common_dir(repo).join("objects").join("info");
+    let file = info.join("commit-graph");
+    if file.is_file() {
+        if readable_commit_graph(&file) {
+            CommitGraph::File(canonical(&file))
+        } else {
+            CommitGraph::Unsupported(canonical(&file))
+        }
+    } else if info.join("commit-graphs").is_dir() {
+        CommitGraph::Chain
+    } else {
+        CommitGraph::Missing
+    }
+}
+
+// Whether the header and the chunk table of a commit-graph file are ones
+// libgit2 reads, version 1 with SHA-1 ids and only the chunks it knows
+fn readable_commit_graph(path: &Path) -> bool {
+    const KNOWN: [&[u8]; 6] = [b"OIDF", b"OIDL", b"CDAT", b"EDGE", b"BIDX", b"BDAT"];
+    // The number of chunks is a single byte
+    let mut header = Vec::new();
+    let read = fs::File::open(path).and_then(|f| f.take(8 + 255 * 12).read_to_end(&mut header));
+    if read.is_err() || header.len() < 8 || &header[..6] != b"CGPH\x01\x01" {
+        return false;
+    }
+    (0..usize::from(header[6])).all(|i| {
+        header
+            .get(8 + i * 12..12 + i * 12)
+            .is_some_and(|id| KNOWN.contains(&id))
+    })
+}
+
+// Find a worktree other than this one which has the given branch checked
+// out, moving the branch would change the commit of that checkout behind
+// its back.
+//
+// git2 can list worktrees but not say what they have checked out, libgit2
+// opens the administrative directory of a linked worktree, .git/worktrees/
+// <name> in the common directory, as a repository of its own, so HEAD is
+// read through it rather than parsed by hand.
+fn checked_out_elsewhere(repo: &git2::Repository, refname: &str) -> Option<PathBuf> {
+    let own = canonical(repo.path());
+    let checked_out = |git_dir: &Path| {
+        if canonical(git_dir) == own {
+            return false;
+        }
+        match git2::Repository::open(git_dir) {
+            Ok(other) => other
+                .find_reference("HEAD")
+                .map(|head| head.symbolic_target() == Some(refname))
+                .unwrap_or(false),
+            Err(_) => false,
+        }
+    };
+
+    // The main worktree, unless the repository is bare
+    let common = common_dir(repo);
+    if checked_out(&common) {
+        if let Ok(main) = git2::Repository::open(&common) {
+            if let Some(workdir) = main.workdir() {
+                return Some(canonical(workdir));
+            }
+        }
+    }
+
+    // Linked worktrees record the path of their .git file in gitdir,
+    // relative to the administrative directory with
+    // worktree.useRelativePaths. One whose .git file is gone was deleted
+    // without git worktree remove and has nothing checked out.
+    let entries = fs::read_dir(common.join("worktrees")).ok()?;
+    for entry in entries.flatten() {
+        let admin = entry.path();
+        let gitdir = match fs::read_to_string(admin.join("gitdir")) {
+            Ok(gitdir) => admin.join(gitdir.trim()),
+            Err(_) => continue,
+        };
+        if !gitdir.exists() || !checked_out(&admin) {
+            continue;
+        }
+        return Some(canonical(gitdir.parent().unwrap_or(&gitdir)));
+    }
+
+    None
+}
+
+// Refuse to move a branch that another worktree has checked out, like git
+// rebase does, or only warn about it with --force
+fn guard_checked_out(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+    refname: &str,
+) -> Result<(), GitSquashError> {
+    let path = match checked_out_elsewhere(repo, refname) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let branch = refname.trim_start_matches("refs/heads/").to_string();
+    if !opts.force {
+        return Err(GitSquashError::CheckedOutElsewhere(branch, path));
+    }
+    opts.report(
+        Level::Warning,
+        &format!(
+            "{} is checked out in the worktree at {}, its index and working copy will not \
+             match the squashed branch",
+            branch,
+            path.display()
+        ),
+    );
+    Ok(())
+}
+
+fn submodule_ignore(value: &str) -> Option<git2::SubmoduleIgnore> {
+    match value {
+        "none" => Some(git2::SubmoduleIgnore::None),
+        "untracked" => Some(git2::SubmoduleIgnore::Untracked),
+        "dirty" => Some(git2::SubmoduleIgnore::Dirty),
+        "all" => Some(git2::SubmoduleIgnore::All),
+        _ => None,
+    }
+}
+
+// The path and status of each change in the index and working copy, without
+// the submodules whose changes --ignore-submodules or
+// diff.ignoreSubmodules say to ignore. Without either libgit2 honors
+// submodule.<name>.ignore.
+fn worktree_status(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+) -> Result<Vec<(String, git2::Status)>, GitSquashError> {
+    let ignore = match opts.ignore_submodules {
+        Some(ref value) => Some(value.to_string()),
+        None => repo.config()?.get_string("diff.ignoreSubmodules").ok(),
+    };
+
+    // Only ask libgit2 for what can make the repo dirty. Ignored files never
+    // count and untracked ones only with --strict, so neither is scanned
+    // unless needed, and renames do not matter for a yes or no answer.
+    let mut options = git2::StatusOptions::new();
+    options
+        .include_ignored(false)
+        .include_untracked(opts.strict || opts.wip_untracked)
+        .recurse_untracked_dirs(false)
+        .renames_head_to_index(false)
+        .renames_index_to_workdir(false)
+        .exclude_submodules(ignore.as_deref() == Some("all"));
+    let statuses = repo.statuses(Some(&mut options))?;
+
+    let ignore = match ignore {
+        Some(ref value) if submodule_ignore(value).is_some() => value,
+        _ => {
+            return Ok(statuses
+                .iter()
+                .map(|s| {
+                    (
+                        String::from_utf8_lossy(s.path_bytes()).into_owned(),
+                        s.status(),
+                    )
+                })
+                .collect())
+        }
+    };
+
+    let mut changed = git2::SubmoduleStatus::empty();
+    changed.insert(git2::SubmoduleStatus::INDEX_ADDED);
+    changed.insert(git2::SubmoduleStatus::INDEX_DELETED);
+    changed.insert(git2::SubmoduleStatus::INDEX_MODIFIED);
+    changed.insert(git2::SubmoduleStatus::WD_ADDED);
+    changed.insert(git2::SubmoduleStatus::WD_DELETED);
+    changed.insert(git2::SubmoduleStatus::WD_MODIFIED);
+    changed.insert(git2::SubmoduleStatus::WD_INDEX_MODIFIED);
+    changed.insert(git2::SubmoduleStatus::WD_WD_MODIFIED);
+    changed.insert(git2::SubmoduleStatus::WD_UNTRACKED);
+
+    let submodules = repo.submodules()?;
+    let mut kept = Vec::new();
+    for entry in statuses.iter() {
+        let submodule = submodules
+            .iter()
+            .find(|s| s.path().to_str().map(str::as_bytes) == Some(entry.path_bytes()));
+        if let Some(name) = submodule.and_then(|s| s.name()) {
+            if !repo
+                .submodule_status(name, submodule_ignore(ignore).unwrap())?
+                .intersects(changed)
+            {
+                continue;
+            }
+        }
+        kept.push((
+            String::from_utf8_lossy(entry.path_bytes()).into_owned(),
+            entry.status(),
+        ));
+    }
+    Ok(kept)
+}
+
+// Check if the index or working copy have changes
+fn is_dirty(statuses: &[(String, git2::Status)], untracked: bool) -> bool {
+    !dirt(statuses, untracked).is_empty()
+}
+
+// The changed paths in the index and working copy, each with the state
+// that best describes it: conflicted, staged, deleted, modified or
+// untracked. Untracked files are not touched by a squash so they only count
+// when asked for, ignored files never do.
+fn dirt(statuses: &[(String, git2::Status)], untracked: bool) -> Vec<(String, FileState)> {
+    let mut staged = git2::Status::empty();
+    staged.insert(git2::Status::INDEX_NEW);
+    staged.insert(git2::Status::INDEX_MODIFIED);
+    staged.insert(git2::Status::INDEX_DELETED);
+    staged.insert(git2::Status::INDEX_RENAMED);
+    staged.insert(git2::Status::INDEX_TYPECHANGE);
+
+    let mut modified = git2::Status::empty();
+    modified.insert(git2::Status::WT_MODIFIED);
+    modified.insert(git2::Status::WT_TYPECHANGE);
+    modified.insert(git2::Status::WT_RENAMED);
+
+    let states = [
+        (git2::Status::CONFLICTED, FileState::Conflicted),
+        (staged, FileState::Staged),
+        (git2::Status::WT_DELETED, FileState::Deleted),
+        (modified, FileState::Modified),
+        (git2::Status::WT_NEW, FileState::Untracked),
+    ];
+
+    let mut found = Vec::new();
+    for &(ref path, status) in statuses {
+        let state = states.iter().find(|&&(s, _)| status.intersects(s));
+        match state {
+            Some(&(_, FileState::Untracked)) if !untracked => {}
+            Some(&(_, state)) => found.push((path.to_string(), state)),
+            None => {}
+        }
+    }
+    found
+}
+
+// Stage the changes in the working copy like git add -u, or git add -A with
+// the untracked files too, for --wip. The index is only changed in memory.
+fn stage_changes(index: &mut git2::Index, untracked: bool) -> Result<(), git2::Error> {
+    index.update_all(["*"].iter(), None)?;
+    if untracked {
+        index.add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)?;
+    }
+    Ok(())
+}
+
+// The pattern in --protect, or else squash.protectedBranches, that the
+// branch matches. Without either main and master are protected.
+fn protected_by(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+    branch: &str,
+) -> Result<Option<String>, GitSquashError> {
+    if opts.no_protect {
+        return Ok(None);
+    }
+
+    let patterns: Vec<String> = if !opts.protect.is_empty() {
+        opts.protect.clone()
+    } else {
+        match repo.config()?.get_string("squash.protectedBranches") {
+            Ok(value) => value
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|p| !p.is_empty())
+                .map(String::from)
+                .collect(),
+            Err(_) => vec!["main".to_string(), "master".to_string()],
+        }
+    };
+
+    Ok(patterns.into_iter().find(|p| glob_match(p, branch)))
+}
+
+// Match a name against a glob where * matches any run of characters,
+// including '/', and ? a single one
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // Where to resume after the last *, the pattern after it and the name
+    // it has consumed up to
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((after, consumed)) => {
+                    p = after;
+                    n = consumed + 1;
+                    star = Some((after, consumed + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Count the commits that carry a signature, which squashing will discard
+fn count_signed(repo: &git2::Repository, commits: &[git2::Oid]) -> Result<usize, git2::Error> {
+    let mut signed = 0;
+    for oid in commits {
+        match repo.extract_signature(oid, None) {
+            Ok(_) => signed += 1,
+            Err(ref e) if e.code() == git2::ErrorCode::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(signed)
+}
+
+// Run a signature checking program with the signed data on stdin
+fn run_verifier(command: &mut process::Command, data: &[u8]) -> Result<process::Output, String> {
+    let mut child = command
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("cannot run {:?}: {}", command.get_program(), e))?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(data)
+        .map_err(|e| e.to_string())?;
+    child.wait_with_output().map_err(|e| e.to_string())
+}
+
+// Check an OpenPGP signature with the gpg program. Like git merge
+// --verify-signatures the key has to be trusted at least marginally.
+fn verify_gpg(program: &str, path: &Path, data: &[u8]) -> Result<(), String> {
+    let output = run_verifier(
+        process::Command::new(program)
+            .args(["--status-fd=1", "--verify"])
+            .arg(path)
+            .arg("-"),
+        data,
+    )?;
+
+    let status = String::from_utf8_lossy(&output.stdout);
+    if !status.contains("[GNUPG:] GOODSIG ") {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(stderr.lines().last().unwrap_or("bad signature").to_string());
+    }
+    let trusted = status.lines().any(|l| {
+        l.starts_with("[GNUPG:] TRUST_MARGINAL")
+            || l.starts_with("[GNUPG:] TRUST_FULLY")
+            || l.starts_with("[GNUPG:] TRUST_ULTIMATE")
+    });
+    if !trusted {
+        return Err("the key is not trusted".to_string());
+    }
+    Ok(())
+}
+
+// Check an SSH signature against gpg.ssh.allowedSignersFile, which is
+// what decides which keys are trusted
+fn verify_ssh(config: &git2::Config, path: &Path, data: &[u8]) -> Result<(), String> {
+    let allowed = config
+        .get_path("gpg.ssh.allowedSignersFile")
+        .map_err(|_| "gpg.ssh.allowedSignersFile is not set".to_string())?;
+    let program = config
+        .get_string("gpg.ssh.program")
+        .unwrap_or_else(|_| "ssh-keygen".to_string());
+
+    let found = run_verifier(
+        process::Command::new(&program)
+            .args(["-Y", "find-principals", "-f"])
+            .arg(&allowed)
+            .arg("-s")
+            .arg(path),
+        &[],
+    )?;
+    let principals = String::from_utf8_lossy(&found.stdout);
+    let principal = match principals.lines().next() {
+        Some(principal) if found.status.success() => principal.to_string(),
+        _ => return Err("the key is not in gpg.ssh.allowedSignersFile".to_string()),
+    };
+
+    let output = run_verifier(
+        process::Command::new(&program)
+            .args(["-Y", "verify", "-n", "git", "-f"])
+            .arg(&allowed)
+            .args(["-I", &principal, "-s"])
+            .arg(path),
+        data,
+    )?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(stderr.lines().last().unwrap_or("bad signature").to_string());
+    }
+    Ok(())
+}
+
+// With --verify-signatures every squashed commit has to have a good
+// signature by a trusted key, like git merge --verify-signatures
+fn verify_signatures(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+    commits: &[git2::Oid],
+) -> Result<(), GitSquashError> {
+    let config = repo.config()?;
+    let path = repo.path().join("SQUASH_SIGNATURE");
+    for oid in commits {
+        let (signature, data) = match repo.extract_signature(oid, None) {
+            Ok(signed) => signed,
+            Err(ref e) if e.code() == git2::ErrorCode::NotFound => {
+                return Err(GitSquashError::BadSignature(
+                    *oid,
+                    "it is not signed".to_string(),
+                ))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        fs::write(&path, &*signature)
+            .map_err(|e| GitSquashError::BadSignature(*oid, e.to_string()))?;
+        let result = if signature.starts_with(b"-----BEGIN SSH SIGNATURE-----") {
+            verify_ssh(&config, &path, &data)
+        } else {
+            verify_gpg(&gpg_program(opts, &config), &path, &data)
+        };
+        let _ = fs::remove_file(&path);
+
+        result.map_err(|reason| GitSquashError::BadSignature(*oid, reason))?;
+        debug!("{} has a good signature", oid);
+    }
+    Ok(())
+}
+
+// The authors of the commits other than the one the squashed commit will
+// have, by email like git shortlog -e, with how many commits each wrote
+fn foreign_authors(
+    repo: &git2::Repository,
+    commits: &[git2::Oid],
+    author: &git2::Signature,
+) -> Result<Vec<(String, usize)>, git2::Error> {
+    let email = String::from_utf8_lossy(author.email_bytes()).to_lowercase();
+    let mut authors: Vec<(String, String, usize)> = Vec::new();
+    for oid in commits.iter().rev() {
+        let commit = repo.find_commit(*oid)?;
+        let other = commit.author();
+        let other_email = String::from_utf8_lossy(other.email_bytes()).to_lowercase();
+        if other_email == email {
+            continue;
+        }
+        match authors.iter_mut().find(|a| a.0 == other_email) {
+            Some(found) => found.2 += 1,
+            None => {
+                let ident = format!(
+                    "{} <{}>",
+                    String::from_utf8_lossy(other.name_bytes()),
+                    String::from_utf8_lossy(other.email_bytes())
+                );
+                authors.push((other_email, ident, 1));
+            }
+        }
+    }
+    Ok(authors
+        .into_iter()
+        .map(|(_, ident, count)| (ident, count))
+        .collect())
+}
+
+// Ask whether to squash commits by other authors anyway, the reporter
+// decides who can answer
+fn confirm_foreign_authors(opts: &SquashOptions, authors: &[(String, usize)]) -> bool {
+    let reporter = match opts.reporter {
+        Some(ref reporter) => reporter,
+        None => return false,
+    };
+
+    let mut question = "Some of the squashed commits are by other authors:".to_string();
+    for &(ref author, count) in authors {
+        let commits = if count == 1 { "commit" } else { "commits" };
+        question.push_str(&format!("\n    {} ({} {})", author, count, commits));
+    }
+    question.push_str("\nSquash them anyway?");
+    reporter.confirm(&question)
+}
+
+// The start and the tip of a range like A..B, the start has to be an
+// ancestor of the tip for the range to be a line of commits on top of it
+fn resolve_range(
+    repo: &git2::Repository,
+    spec: &str,
+) -> Result<(git2::Oid, git2::Oid), GitSquashError> {
+    let revspec = repo.revparse(spec)?;
+    if !revspec.mode().contains(git2::RevparseMode::RANGE)
+        || revspec.mode().contains(git2::RevparseMode::MERGE_BASE)
+    {
+        return Err(GitSquashError::InvalidRange(spec.to_string()));
+    }
+    let (from, to) = match (revspec.from(), revspec.to()) {
+        (Some(from), Some(to)) => (from.peel_to_commit()?.id(), to.peel_to_commit()?.id()),
+        _ => return Err(GitSquashError::InvalidRange(spec.to_string())),
+    };
+    if from != to && !repo.graph_descendant_of(to, from)? {
+        let mut ends = spec.splitn(2, "..");
+        return Err(GitSquashError::NotAncestor(
+            ends.next().unwrap().to_string(),
+            ends.next().unwrap().to_string(),
+        ));
+    }
+    Ok((from, to))
+}
+
+// Map a failed lookup of a base to an error naming what the user typed
+fn unknown_base(name: &str, e: git2::Error) -> GitSquashError {
+    match e.code() {
+        git2::ErrorCode::NotFound | git2::ErrorCode::InvalidSpec => {
+            GitSquashError::UnknownBase(name.to_string())
+        }
+        _ => GitSquashError::Git2(e),
+    }
+}
+
+fn branch_tip(
+    repo: &git2::Repository,
+    short: &str,
+    kind: git2::BranchType,
+    name: &str,
+) -> Result<git2::Oid, GitSquashError> {
+    let branch = repo
+        .find_branch(short, kind)
+        .map_err(|e| unknown_base(name, e))?;
+    branch
+        .into_reference()
+        .target()
+        .ok_or_else(|| GitSquashError::SymbolicRef(name.to_string()))
+}
+
+fn branch_exists(
+    repo: &git2::Repository,
+    name: &str,
+    kind: git2::BranchType,
+) -> Result<bool, GitSquashError> {
+    match repo.find_branch(name, kind) {
+        Ok(_) => Ok(true),
+        Err(ref e)
+            if e.code() == git2::ErrorCode::NotFound
+                || e.code() == git2::ErrorCode::InvalidSpec =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(GitSquashError::Git2(e)),
+    }
+}
+
+// Find the tip of a base named on the command line or in config
+fn resolve_base(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+    name: &str,
+) -> Result<git2::Oid, GitSquashError> {
+    // Fully qualified names are routed by their namespace
+    if let Some(short) = name.strip_prefix("refs/heads/") {
+        return branch_tip(repo, short, git2::BranchType::Local, name);
+    }
+    if let Some(short) = name.strip_prefix("refs/remotes/") {
+        return branch_tip(repo, short, git2::BranchType::Remote, name);
+    }
+    if name.starts_with("refs/") {
+        // Tags and anything else are peeled to the commit they point at
+        let reference = repo
+            .find_reference(name)
+            .map_err(|e| unknown_base(name, e))?;
+        return Ok(reference.peel_to_commit()?.id());
+    }
+
+    // Upstream shorthands like @{u} are only understood by revparse
+    if !name.contains("@{") {
+        // A name like release/2024.06 is looked up as a local branch before
+        // a remote-tracking one, so it is never split into a remote and a
+        // branch while a local branch of that name exists. Both come before
+        // a tag, unlike in git, so a name that is more than one of them is
+        // pointed out.
+        let local = branch_exists(repo, name, git2::BranchType::Local)?;
+        let remote = branch_exists(repo, name, git2::BranchType::Remote)?;
+        let tag = repo.find_reference(&format!("refs/tags/{}", name)).is_ok();
+        let matches: Vec<String> = [(local, "heads"), (remote, "remotes"), (tag, "tags")]
+            .iter()
+            .filter(|&&(found, _)| found)
+            .map(|&(_, namespace)| format!("refs/{}/{}", namespace, name))
+            .collect();
+        if matches.len() > 1 {
+            opts.report(
+                Level::Warning,
+                &format!(
+                    "refname '{}' is ambiguous, using {} rather than {}",
+                    name,
+                    matches[0],
+                    matches[1..].join(" or ")
+                ),
+            );
+            opts.report(Level::Hint, "pass the full refname to choose another one");
+        }
+        if local {
+            return branch_tip(repo, name, git2::BranchType::Local, name);
+        }
+        if remote {
+            return branch_tip(repo, name, git2::BranchType::Remote, name);
+        }
+    }
+
+    // Anything else git understands, like tags and shas
+    let object = repo
+        .revparse_single(name)
+        .map_err(|e| unknown_base(name, e))?;
+    Ok(object.peel_to_commit()?.id())
+}
+
+// The config key holding the squash base of a branch
+fn branch_base_key(branch: &str) -> String {
+    format!("branch.{}.squashBase", branch)
+}
+
+// Read the base from git config, if one is configured. The base of the
+// branch being squashed wins over the repository wide one.
+fn config_base(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+    squashed: &git2::Reference,
+) -> Result<Option<Base>, GitSquashError> {
+    let config = repo.config()?;
+
+    let mut keys = Vec::new();
+    if squashed.is_branch() {
+        keys.push(branch_base_key(&String::from_utf8_lossy(
+            squashed.shorthand_bytes(),
+        )));
+    }
+    keys.push("squash.base".to_string());
+    keys.push("git-squash.base".to_string());
+
+    for key in keys {
+        match config.get_string(&key) {
+            Ok(name) => {
+                let oid = resolve_base(repo, opts, &name)?;
+                return Ok(Some(Base {
+                    name,
+                    oid,
+                    source: BaseSource::Config(key),
+                }));
+            }
+            Err(ref e) if e.code() == git2::ErrorCode::NotFound => {}
+            Err(e) => return Err(GitSquashError::Git2(e)),
+        }
+    }
+
+    Ok(None)
+}
+
+// Guess the mainline of the repository from the remote HEAD, the
+// configured default branch and finally the conventional names.
+fn detect_default_branch(repo: &git2::Repository) -> Result<Base, GitSquashError> {
+    if let Ok(origin_head) = repo.find_reference("refs/remotes/origin/HEAD") {
+        if let Ok(resolved) = origin_head.resolve() {
+            if let (Some(name), Some(oid)) = (resolved.shorthand(), resolved.target()) {
+                return Ok(Base {
+                    name: name.to_string(),
+                    oid,
+                    source: BaseSource::OriginHead,
+                });
+            }
+        }
+    }
+
+    let mut candidates = Vec::new();
+    if let Ok(name) = repo.config()?.get_string("init.defaultBranch") {
+        candidates.push(name);
+    }
+    candidates.push("main".to_string());
+    candidates.push("master".to_string());
+
+    for name in candidates {
+        match repo.find_branch(&name, git2::BranchType::Local) {
+            Ok(branch) => {
+                if let Some(oid) = branch.get().target() {
+                    return Ok(Base {
+                        name,
+                        oid,
+                        source: BaseSource::DefaultBranch,
+                    });
+                }
+            }
+            Err(ref e) if e.code() == git2::ErrorCode::NotFound => {}
+            Err(e) => return Err(GitSquashError::Git2(e)),
+        }
+    }
+
+    Err(GitSquashError::NoBase)
+}
+
+// Pick the base when none was given, preferring the upstream of the
+// branch being squashed. Falling back is mentioned unless quiet.
+fn default_base(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+    squashed: git2::Reference,
+) -> Result<Base, GitSquashError> {
+    if !squashed.is_branch() {
+        return detect_default_branch(repo);
+    }
+
+    let current = git2::Branch::wrap(squashed);
+    let current_name = String::from_utf8_lossy(current.name_bytes()?).into_owned();
+    match current.upstream() {
+        Ok(upstream) => {
+            let name = String::from_utf8_lossy(upstream.name_bytes()?).into_owned();
+            let oid = upstream
+                .get()
+                .target()
+                .ok_or_else(|| GitSquashError::SymbolicRef(name.clone()))?;
+            Ok(Base {
+                name,
+                oid,
+                source: BaseSource::Upstream(current_name),
+            })
+        }
+        Err(ref e) if e.code() == git2::ErrorCode::NotFound => {
+            if !opts.quiet {
+                opts.report(
+                    Level::Note,
+                    &format!("{} has no upstream configured", current_name),
+                );
+                opts.report(
+                    Level::Hint,
+                    "set one with `git branch --set-upstream-to=<branch>`",
+                );
+            }
+            detect_default_branch(repo)
+        }
+        Err(e) => Err(GitSquashError::Git2(e)),
+    }
+}
+
+// How a fixup!, squash! or amend! commit is folded into its target
+enum Fold {
+    Fixup,
+    Squash,
+    Amend,
+}
+
+// A commit kept on the branch with the commits folded into it, like a pick
+// in git rebase --autosquash
+struct Pick {
+    oid: git2::Oid,
+    folds: Vec<(Fold, git2::Oid)>,
+}
+
+// Split a subject like "fixup! fixup! Add x" into how it is folded and the
+// subject of its target
+fn fold_kind(subject: &str) -> Option<(Fold, &str)> {
+    let (fold, mut target) = if let Some(rest) = subject.strip_prefix("fixup! ") {
+        (Fold::Fixup, rest)
+    } else if let Some(rest) = subject.strip_prefix("squash! ") {
+        (Fold::Squash, rest)
+    } else if let Some(rest) = subject.strip_prefix("amend! ") {
+        (Fold::Amend, rest)
+    } else {
+        return None;
+    };
+
+    while let Some(rest) = ["fixup! ", "squash! ", "amend! "]
+        .iter()
+        .find_map(|p| target.strip_prefix(p))
+    {
+        target = rest;
+    }
+    Some((fold, target))
+}
+
+// Find the target of a fixup among the commits before it the way git does,
+// an exact subject first, then a commit id, then a subject prefix
+fn fixup_target(repo: &git2::Repository, earlier: &[git2::Commit], target: &str) -> Option<usize> {
+    if let Some(i) = earlier.iter().position(|c| summary(c) == target) {
+        return Some(i);
+    }
+    if !target.contains(' ') {
+        if let Ok(oid) = repo
+            .revparse_single(target)
+            .and_then(|o| o.peel_to_commit())
+        {
+            if let Some(i) = earlier.iter().position(|c| c.id() == oid.id()) {
+                return Some(i);
+            }
+        }
+    }
+    earlier.iter().position(|c| summary(c).starts_with(target))
+}
+
+// The commits being squashed oldest first, the branch can only be rebuilt
+// when it has no merges
+fn linear_commits<'r>(
+    repo: &'r git2::Repository,
+    commits_to_squash: &[git2::Oid],
+) -> Result<Vec<git2::Commit<'r>>, GitSquashError> {
+    let mut commits = Vec::new();
+    for oid in commits_to_squash.iter().rev() {
+        let commit = repo.find_commit(*oid)?;
+        if commit.parent_count() > 1 {
+            return Err(GitSquashError::Rewrite(format!(
+                "{} is a merge commit",
+                short_id(*oid)
+            )));
+        }
+        commits.push(commit);
+    }
+    Ok(commits)
+}
+
+// The editor to run like git var GIT_EDITOR, or GIT_SEQUENCE_EDITOR for a
+// todo list
+fn editor(config: &git2::Config, sequence: bool) -> String {
+    if sequence {
+        if let Ok(editor) = env::var("GIT_SEQUENCE_EDITOR") {
+            return editor;
+        }
+        if let Ok(editor) = config.get_string("sequence.editor") {
+            return editor;
+        }
+    }
+    if let Ok(editor) = env::var("GIT_EDITOR") {
+        return editor;
+    }
+    if let Ok(editor) = config.get_string("core.editor") {
+        return editor;
+    }
+    env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string())
+}
+
+// Let the user edit a file, the editor is run through the shell like git
+// does so it can have arguments
+fn run_editor(editor: &str, path: &Path) -> Result<(), GitSquashError> {
+    let status = process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(editor)
+        .arg(path)
+        .status()
+        .map_err(|_| GitSquashError::Editor(editor.to_string()))?;
+    if !status.success() {
+        return Err(GitSquashError::Editor(editor.to_string()));
+    }
+    Ok(())
+}
+
+// The diff from the base to the tip of the branch, as the squashed commit
+// will show it
+fn squash_diff(repo: &git2::Repository, outcome: &SquashOutcome) -> Result<String, git2::Error> {
+    let old_tree = repo.find_commit(outcome.onto)?.tree()?;
+    let new_tree = repo.find_commit(outcome.head)?.tree()?;
+    let diff = repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+
+    let mut patch = Vec::new();
+    diff.print(git2::DiffFormat::Patch, |_, _, line| {
+        if let origin @ ('+' | '-' | ' ') = line.origin() {
+            patch.push(origin as u8);
+        }
+        patch.extend_from_slice(line.content());
+        true
+    })?;
+    Ok(String::from_utf8_lossy(&patch).into_owned())
+}
+
+// Whether the diff goes in the editor, with --verbose or commit.verbose
+fn verbose_edit(repo: &git2::Repository, opts: &SquashOptions) -> Result<bool, git2::Error> {
+    if opts.verbose {
+        return Ok(true);
+    }
+    let config = repo.config()?;
+    Ok(match config.get_bool("commit.verbose") {
+        Ok(verbose) => verbose,
+        Err(_) => config.get_i32("commit.verbose").is_ok_and(|v| v > 0),
+    })
+}
+
+// Let the user edit the message of the squashed commit, with the commits
+// being squashed listed in comments. With --cleanup=scissors the messages
+// of all of them follow below a scissors line instead. A verbose edit
+// ends with the diff of the squash below the scissors line, which is cut
+// off again whatever the cleanup.
+fn edit_message(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+    message: &str,
+    outcome: &SquashOutcome,
+    cleanup: Cleanup,
+    comment: char,
+    verbose: bool,
+) -> Result<String, GitSquashError> {
+    let scissors = commented(
+        &format!(
+            "{}\n\
+             Do not modify or remove the line above.\n\
+             Everything below it will be ignored.",
+            SCISSORS
+        ),
+        comment,
+    );
+    let mut buffer = format!("{}\n\n", message.trim_end());
+    let hint = match cleanup {
+        Cleanup::Default | Cleanup::Strip => format!(
+            "Lines starting\nwith '{}' will be ignored, and an empty message aborts the squash.",
+            comment
+        ),
+        Cleanup::Scissors => "An empty message\naborts the squash.".to_string(),
+        Cleanup::Whitespace | Cleanup::Verbatim => format!(
+            "Lines starting\nwith '{}' will be kept, you may remove them yourself if you want\n\
+             to. An empty message aborts the squash.",
+            comment
+        ),
+    };
+    if cleanup == Cleanup::Scissors {
+        buffer.push_str(&scissors);
+    }
+    buffer.push_str(&commented(
+        &format!(
+            "Please enter the message for the squashed commit. {}\n\
+             \n\
+             Commits being squashed onto {}:",
+            hint,
+            short_id(outcome.onto)
+        ),
+        comment,
+    ));
+    for commit in &outcome.commits {
+        buffer.push_str(&commented(
+            &format!("  {} {}", short_id(commit.id), commit.summary),
+            comment,
+        ));
+    }
+    if cleanup == Cleanup::Scissors {
+        for commit in &outcome.commits {
+            let commit = repo.find_commit(commit.id)?;
+            buffer.push('\n');
+            buffer.push_str(&commented(
+                &format!("This is the message of {}:", short_id(commit.id())),
+                comment,
+            ));
+            buffer.push('\n');
+            buffer.push_str(&decode_message(opts, &commit));
+        }
+    }
+    if verbose {
+        if cleanup != Cleanup::Scissors {
+            buffer.push_str(&scissors);
+        }
+        buffer.push_str(&squash_diff(repo, outcome)?);
+    }
+
+    let path = repo.path().join("SQUASH_EDITMSG");
+    fs::write(&path, buffer).map_err(|e| GitSquashError::Editor(e.to_string()))?;
+    run_editor(&editor(&repo.config()?, false), &path)?;
+    let edited = fs::read_to_string(&path).map_err(|e| GitSquashError::Editor(e.to_string()))?;
+
+    if verbose {
+        return Ok(cut_at_scissors(&edited, comment).to_string());
+    }
+    Ok(edited)
+}
+
+// Ask which commits to keep and which to fold into the commit before them
+// with a todo list like git rebase -i
+fn interactive_plan(
+    repo: &git2::Repository,
+    commits_to_squash: &[git2::Oid],
+    outcome: &SquashOutcome,
+) -> Result<(Vec<Pick>, Vec<git2::Oid>), GitSquashError> {
+    let commits = linear_commits(repo, commits_to_squash)?;
+
+    let mut todo = String::new();
+    for (i, commit) in commits.iter().enumerate() {
+        todo.push_str(&format!(
+            "{} {} {}\n",
+            if i == 0 { "keep" } else { "squash" },
+            short_id(commit.id()),
+            summary(commit)
+        ));
+    }
+    todo.push_str(&format!(
+        "\n# Squash {} commits on {} onto {}\n\
+         #\n\
+         # Commands:\n\
+         # k, keep <commit> = keep the commit\n\
+         # s, squash <commit> = fold the commit into the one before it\n\
+         # d, drop <commit> = remove the commit from the branch\n\
+         #\n\
+         # The commits cannot be reordered.\n\
+         # If you remove everything, the squash will be aborted.\n",
+        commits.len(),
+        outcome.branch,
+        short_id(outcome.onto)
+    ));
+
+    let path = repo.path().join("git-squash-todo");
+    fs::write(&path, todo).map_err(|e| GitSquashError::Todo(e.to_string()))?;
+    let edited = run_editor(&editor(&repo.config()?, true), &path)
+        .and_then(|_| fs::read_to_string(&path).map_err(|e| GitSquashError::Todo(e.to_string())));
+    let _ = fs::remove_file(&path);
+    let edited = edited?;
+
+    let lines: Vec<&str> = edited
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect();
+    if lines.is_empty() {
+        return Err(GitSquashError::Todo(
+            "it is empty, nothing to do".to_string(),
+        ));
+    }
+    if lines.len() != commits.len() {
+        return Err(GitSquashError::Todo(format!(
+            "it has {} lines but there are {} commits",
+            lines.len(),
+            commits.len()
+        )));
+    }
+
+    let mut plan: Vec<Pick> = Vec::new();
+    let mut dropped = Vec::new();
+    for (line, commit) in lines.iter().zip(&commits) {
+        let mut words = line.split_whitespace();
+        let verb = words.next().unwrap();
+        let id = words.next().unwrap_or("");
+        if id.len() < 4 || !commit.id().to_string().starts_with(id) {
+            return Err(GitSquashError::Todo(format!(
+                "expected {} but found '{}', the commits cannot be reordered",
+                short_id(commit.id()),
+                line
+            )));
+        }
+        match verb {
+            "k" | "keep" => plan.push(Pick {
+                oid: commit.id(),
+                folds: Vec::new(),
+            }),
+            "s" | "squash" => match plan.last_mut() {
+                Some(pick) => pick.folds.push((Fold::Squash, commit.id())),
+                None => {
+                    return Err(GitSquashError::Todo(format!(
+                        "cannot squash {} without a commit before it",
+                        short_id(commit.id())
+                    )))
+                }
+            },
+            "d" | "drop" => dropped.push(commit.id()),
+            _ => return Err(GitSquashError::Todo(format!("unknown command '{}'", verb))),
+        }
+    }
+
+    Ok((plan, dropped))
+}
+
+// Order the commits on the branch like git rebase --autosquash, each fixup
+// moved after its target
+fn autosquash_plan(
+    repo: &git2::Repository,
+    commits_to_squash: &[git2::Oid],
+) -> Result<Vec<Pick>, GitSquashError> {
+    let commits = linear_commits(repo, commits_to_squash)?;
+
+    let mut plan: Vec<Pick> = Vec::new();
+    // The pick each commit ended up in, so a fixup of a fixup goes to the
+    // same target
+    let mut pick_of: Vec<usize> = Vec::new();
+    for (i, commit) in commits.iter().enumerate() {
+        let summary = summary(commit);
+        let target = fold_kind(&summary).and_then(|(fold, target)| {
+            fixup_target(repo, &commits[..i], target).map(|t| (fold, t))
+        });
+        match target {
+            Some((fold, t)) => {
+                let pick = pick_of[t];
+                plan[pick].folds.push((fold, commit.id()));
+                pick_of.push(pick);
+            }
+            None => {
+                plan.push(Pick {
+                    oid: commit.id(),
+                    folds: Vec::new(),
+                });
+                pick_of.push(plan.len() - 1);
+            }
+        }
+    }
+
+    Ok(plan)
+}
+
+// Apply the change a commit makes to a tree, like git cherry-pick
+fn apply_commit<'r>(
+    repo: &'r git2::Repository,
+    commit: &git2::Commit,
+    onto: &git2::Tree,
+) -> Result<git2::Tree<'r>, GitSquashError> {
+    let ancestor = commit.parent(0)?.tree()?;
+    if ancestor.id() == onto.id() {
+        return Ok(repo.find_tree(commit.tree_id())?);
+    }
+
+    let mut index = repo.merge_trees(&ancestor, onto, &commit.tree()?, None)?;
+    if index.has_conflicts() {
+        return Err(GitSquashError::Rewrite(format!(
+            "{} {} does not apply cleanly",
+            short_id(commit.id()),
+            summary(commit)
+        )));
+    }
+    Ok(repo.find_tree(index.write_tree_to(repo)?)?)
+}
+
+// The message of a commit without its subject
+fn message_body(message: &str) -> &str {
+    match message.find("\n\n") {
+        Some(end) => message[end..].trim(),
+        None => "",
+    }
+}
+
+// Rebuild the branch on top of onto following the plan. Commits without
+// fixups whose parent did not change are kept as they are.
+fn rebuild(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+    plan: &[Pick],
+    onto: git2::Oid,
+    committer: &git2::Signature,
+    signing: Option<&Signing>,
+    cleanup: Cleanup,
+) -> Result<git2::Oid, GitSquashError> {
+    let mut parent = repo.find_commit(onto)?;
+    for pick in plan {
+        let commit = repo.find_commit(pick.oid)?;
+        if pick.folds.is_empty() && commit.parent_id(0)? == parent.id() {
+            parent = commit;
+            continue;
+        }
+
+        let mut tree = apply_commit(repo, &commit, &parent.tree()?)?;
+        // Folded messages are combined as text, and written back in the
+        // encoding they share. When they were in different encodings the
+        // result is UTF-8.
+        let mut message = decode_message(opts, &commit);
+        let mut encoding = commit.message_encoding().map(String::from);
+        for &(ref fold, oid) in &pick.folds {
+            let fixup = repo.find_commit(oid)?;
+            tree = apply_commit(repo, &fixup, &tree)?;
+            if fixup.message_encoding() != encoding.as_deref() && !matches!(*fold, Fold::Fixup) {
+                opts.report(
+                    Level::Warning,
+                    &format!(
+                        "{} and {} have messages in different encodings, writing the \
+                         combined message as UTF-8",
+                        short_id(commit.id()),
+                        short_id(oid)
+                    ),
+                );
+                encoding = None;
+            }
+            let fixup_message = decode_message(opts, &fixup);
+            let body = message_body(&fixup_message);
+            // A squash! subject only names the target, other commits are
+            // folded with their whole message
+            let squashed = match fold_kind(&summary(&fixup)) {
+                Some(_) => body,
+                None => fixup_message.trim(),
+            };
+            match *fold {
+                Fold::Fixup => {}
+                Fold::Squash if squashed.is_empty() => {}
+                Fold::Squash => message = format!("{}\n\n{}\n", message.trim_end(), squashed),
+                Fold::Amend => message = format!("{}\n", body),
+            }
+        }
+
+        // A commit that is only moved keeps its message byte for byte
+        let (message, encoding) = if pick.folds.is_empty() {
+            (
+                commit.message_bytes().to_vec(),
+                commit.message_encoding().map(String::from),
+            )
+        } else {
+            let comment = comment_char(&repo.config()?, &message);
+            let message = cleanup_message(&message, cleanup, false, comment);
+            encode_message(opts, &message, encoding.as_deref())
+        };
+        let committer = if opts.committer_date_is_author_date {
+            retimed(committer, &commit.author().when())?
+        } else {
+            committer.to_owned()
+        };
+        let oid = create_commit(
+            repo,
+            opts,
+            &commit.author(),
+            &committer,
+            &message,
+            encoding.as_deref(),
+            &tree,
+            &[&parent],
+            signing,
+        )?;
+        parent = repo.find_commit(oid)?;
+    }
+
+    Ok(parent.id())
+}
+
+// The base tree with the entry at path replaced by the one in tree, or
+// removed when tree does not have it
+fn restrict_to_path<'r>(
+    repo: &'r git2::Repository,
+    base: &git2::Tree,
+    tree: &git2::Tree,
+    path: &str,
+) -> Result<git2::Tree<'r>, GitSquashError> {
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+    let entry = tree.get_path(Path::new(path)).ok();
+    if components.is_empty() || (entry.is_none() && base.get_path(Path::new(path)).is_err()) {
+        return Err(GitSquashError::NoSuchPath(path.to_string()));
+    }
+
+    let entry = entry.map(|e| (e.id(), e.filemode()));
+    let oid = replace_entry(repo, Some(base), &components, entry)?;
+    Ok(repo.find_tree(oid)?)
+}
+
+fn replace_entry(
+    repo: &git2::Repository,
+    base: Option<&git2::Tree>,
+    components: &[&str],
+    entry: Option<(git2::Oid, i32)>,
+) -> Result<git2::Oid, git2::Error> {
+    let mut builder = repo.treebuilder(base)?;
+    let name = components[0];
+    if components.len() == 1 {
+        match entry {
+            Some((oid, mode)) => {
+                builder.insert(name, oid, mode)?;
+            }
+            None if builder.get(name)?.is_some() => builder.remove(name)?,
+            None => {}
+        }
+        return builder.write();
+    }
+
+    let subtree = match base.and_then(|t| t.get_name(name)) {
+        Some(ref e) if e.kind() == Some(git2::ObjectType::Tree) => Some(repo.find_tree(e.id())?),
+        _ => None,
+    };
+    let oid = replace_entry(repo, subtree.as_ref(), &components[1..], entry)?;
+    // Git does not store empty directories
+    if repo.find_tree(oid)?.is_empty() {
+        if builder.get(name)?.is_some() {
+            builder.remove(name)?;
+        }
+    } else {
+        builder.insert(name, oid, 0o040000)?;
+    }
+    builder.write()
+}
+
+// Open the repository the options point at and squash its branch, with
+// --autostash putting local changes aside while the branch is squashed.
+// They are brought back even if the squash fails.
+pub fn run(opts: &SquashOptions) -> Result<SquashOutcome, GitSquashError> {
+    let mut repo = open_repo(opts)?;
+
+    // A run that changes nothing leaves the changes where they are
+    let stash = if autostash_enabled(&repo, opts)
+        && !read_only(opts)
+        && opts.branch.is_none()
+        && opts.new_branch.is_none()
+        && !opts.print_base
+        && !opts.skip_dirty_check
+        && is_dirty(&worktree_status(&repo, opts)?, opts.strict)
+    {
+        Some(stash_changes(&mut repo, opts)?)
+    } else {
+        None
+    };
+
+    let result = squash(&repo, opts);
+    if let Some(stash) = stash {
+        restore_stash(&mut repo, opts, stash);
+    }
+    result
+}
+
+// Whether to stash local changes, --autostash or else rebase.autoStash
+fn autostash_enabled(repo: &git2::Repository, opts: &SquashOptions) -> bool {
+    opts.autostash.unwrap_or_else(|| {
+        repo.config()
+            .and_then(|c| c.get_bool("rebase.autoStash"))
+            .unwrap_or(false)
+    })
+}
+
+// Whether the run only shows what a squash would do
+fn read_only(opts: &SquashOptions) -> bool {
+    opts.dry_run || opts.preview || opts.stat_only
+}
+
+// Stash the index and working copy, with untracked files when they count as
+// changes
+fn stash_changes(
+    repo: &mut git2::Repository,
+    opts: &SquashOptions,
+) -> Result<git2::Oid, GitSquashError> {
+    let stasher = repo.signature()?;
+    let mut flags = git2::StashFlags::DEFAULT;
+    if opts.strict {
+        flags.insert(git2::StashFlags::INCLUDE_UNTRACKED);
+    }
+    let oid = repo.stash_save(&stasher, "git-squash autostash", Some(flags))?;
+    debug!("stashed local changes as {}", oid);
+    if !opts.quiet {
+        opts.report(
+            Level::Status,
+            &format!("Created autostash: {}", short_id(oid)),
+        );
+    }
+    Ok(oid)
+}
+
+// Pop the autostash, keeping it when it does not apply so nothing is lost
+fn restore_stash(repo: &mut git2::Repository, opts: &SquashOptions, oid: git2::Oid) {
+    let mut index = None;
+    let _ = repo.stash_foreach(|i, _, stash| {
+        if *stash == oid {
+            index = Some(i);
+        }
+        index.is_none()
+    });
+
+    // Applying can leave conflicts behind instead of failing, the stash is
+    // only dropped when it went in cleanly
+    let result = match index {
+        Some(i) => {
+            let mut options = git2::StashApplyOptions::new();
+            options.reinstantiate_index();
+            repo.stash_apply(i, Some(&mut options)).and_then(|_| {
+                if repo.index()?.has_conflicts() {
+                    Err(git2::Error::from_str(
+                        "it conflicts with the squashed commit",
+                    ))
+                } else {
+                    repo.stash_drop(i)
+                }
+            })
+        }
+        None => Err(git2::Error::from_str("the autostash is gone")),
+    };
+    match (index, result) {
+        (_, Ok(())) if opts.quiet => {}
+        (_, Ok(())) => opts.report(Level::Status, "Applied autostash."),
+        (Some(i), Err(e)) => {
+            opts.report(
+                Level::Warning,
+                &format!("applying the autostash failed: {}", e),
+            );
+            opts.report(
+                Level::Status,
+                &format!(
+                    "Your changes are safe in stash@{{{}}} ({}), resolve any conflicts and run `git stash drop`, or `git stash pop` it later on a clean tree",
+                    i,
+                    short_id(oid)
+                ),
+            );
+        }
+        (None, Err(e)) => opts.report(
+            Level::Warning,
+            &format!(
+                "the autostash {} could not be applied: {}, recover it with `git stash apply {}`",
+                short_id(oid),
+                e,
+                oid
+            ),
+        ),
+    }
+}
+
+// The message of the squashed commit and its encoding, reused, read from
+// --file or edited, with the trailers collected and cleaned up the way the
+// options say. Nothing is committed, so --preview can show it.
+fn squash_message(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+    message_commit: &git2::Commit,
+    commits_to_squash: &[git2::Oid],
+    outcome: &SquashOutcome,
+) -> Result<(Vec<u8>, Option<String>), GitSquashError> {
+    // The message is edited before anything changes so aborting the editor
+    // leaves the repository as it was. A preview shows the message as it
+    // would be opened in the editor.
+    let edit = !opts.preview
+        && opts.edit.unwrap_or_else(|| {
+            repo.config()
+                .and_then(|c| c.get_bool("squash.edit"))
+                .unwrap_or(false)
+        });
+    // A message from --file replaces the reused one. A commit made with
+    // --allow-empty-message has nothing to reuse, one is made up instead of
+    // writing another commit without a message.
+    let generated = if let Some(ref path) = opts.message_file {
+        Some(read_message_file(&opts.dir, path)?)
+    } else if message_commit
+        .message_bytes()
+        .iter()
+        .all(u8::is_ascii_whitespace)
+    {
+        opts.report(
+            Level::Warning,
+            &format!(
+                "{} has an empty message, using a generated one",
+                short_id(message_commit.id())
+            ),
+        );
+        Some(format!(
+            "Squash {} commits from {}\n",
+            commits_to_squash.len(),
+            outcome.branch
+        ))
+    } else {
+        None
+    };
+    // A reused message keeps its bytes and encoding header unless it is
+    // cleaned up, a new one is written in i18n.commitEncoding like git
+    // commit does. The cleanup comes last, once the message is complete.
+    let cleanup = cleanup_mode(repo, opts);
+    let message = match generated {
+        None if !edit
+            && cleanup == Cleanup::Default
+            && !opts.collect_trailers
+            && !opts.record_squashed =>
+        {
+            (
+                message_commit.message_bytes().to_vec(),
+                message_commit.message_encoding().map(String::from),
+            )
+        }
+        generated => {
+            let reused = generated.is_none() && !edit;
+            let mut text = generated.unwrap_or_else(|| decode_message(opts, message_commit));
+            if opts.collect_trailers {
+                text = collect_trailers(repo, opts, commits_to_squash, &text)?;
+            }
+            let comment = comment_char(&repo.config()?, &text);
+            if edit {
+                let verbose = verbose_edit(repo, opts)?;
+                text = edit_message(repo, opts, &text, outcome, cleanup, comment, verbose)?;
+            }
+            // Like git commit, nothing is committed without a message
+            let mut cleaned = cleanup_message(&text, cleanup, edit, comment);
+            if cleaned.trim().is_empty() && !opts.allow_empty_message {
+                return Err(GitSquashError::EmptyMessage(!text.trim().is_empty()));
+            }
+            // The record goes in after the cleanup and the editor so neither
+            // can drop it
+            if opts.record_squashed {
+                cleaned = record_squashed(&cleaned, commits_to_squash);
+            }
+            if reused {
+                encode_message(opts, &cleaned, message_commit.message_encoding())
+            } else {
+                let label = repo.config()?.get_string("i18n.commitEncoding").ok();
+                encode_message(opts, &cleaned, label.as_deref())
+            }
+        }
+    };
+    Ok(message)
+}
+
+pub fn squash(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+) -> Result<SquashOutcome, GitSquashError> {
+    // A branch other than the checked out one is squashed without
+    // touching HEAD, the index or the working copy. The checked out branch
+    // has to go through the normal path which uses the index.
+    let other_branch = match opts.branch {
+        Some(ref name) => {
+            let branch = local_branch(repo, name)?;
+            if branch.is_head() {
+                return Err(GitSquashError::BranchIsHead(name.to_string()));
+            }
+            Some(branch.into_reference())
+        }
+        None => None,
+    };
+
+    let mut progress = Progress::new(opts);
+
+    // --skip-dirty-check trusts the working copy to be clean instead of
+    // scanning it, staged changes it missed are then caught by the tree
+    // verification, or with --no-verify-tree end up in the squashed commit
+    let mut with_staged = false;
+    if opts.skip_dirty_check {
+        debug!("skipping the check for local changes");
+    } else if read_only(opts) && autostash_enabled(repo, opts) {
+        // The squash itself would stash the changes out of the way
+        debug!("not checking for local changes, they would be stashed");
+    } else if other_branch.is_none() && opts.new_branch.is_none() && !opts.print_base {
+        // Check if the index or working copy have changes
+        progress.update("Checking for local changes...");
+        let statuses = worktree_status(repo, opts);
+        progress.clear();
+        let dirt = dirt(&statuses?, opts.strict || opts.wip_untracked);
+
+        // --wip stages the changes into the squashed commit, except for
+        // conflicts that have to be resolved first
+        if opts.wip {
+            let conflicted: Vec<_> = dirt
+                .iter()
+                .filter(|&&(_, state)| state == FileState::Conflicted)
+                .cloned()
+                .collect();
+            if !conflicted.is_empty() {
+                return Err(GitSquashError::DirtyRepo(conflicted));
+            }
+            with_staged = !dirt.is_empty();
+        } else if !dirt.is_empty() && !opts.allow_dirty {
+            return Err(GitSquashError::DirtyRepo(dirt));
+        }
+        // The squashed tree is written from the index, the working copy is
+        // never touched
+        if !opts.wip {
+            with_staged = dirt.iter().any(|&(_, state)| state == FileState::Staged);
+        }
+        if with_staged && !opts.wip {
+            opts.report(
+                Level::Warning,
+                "the staged changes will be part of the squashed commit",
+            );
+        }
+        if dirt.iter().any(|&(_, state)| state != FileState::Staged) && !opts.wip {
+            opts.report(
+                Level::Warning,
+                "changes that are not staged are left in the working copy as they are",
+            );
+        }
+    }
+
+    let (squashed_ref, mut head) = match other_branch {
+        Some(reference) => {
+            let name = String::from_utf8_lossy(reference.name_bytes()).into_owned();
+            guard_checked_out(repo, opts, &name)?;
+            let tip = reference
+                .target()
+                .ok_or_else(|| GitSquashError::SymbolicRef(name.clone()))?;
+            (Some(name), tip)
+        }
+        None => match repo.refname_to_id("HEAD") {
+            Ok(oid) => (None, oid),
+            // A branch without any commits yet has nothing to squash
+            Err(ref e) if e.code() == git2::ErrorCode::NotFound => {
+                if opts.require_squash {
+                    return Err(GitSquashError::NothingToSquash(0));
+                }
+                let head = repo.find_reference("HEAD")?;
+                let refname = head.symbolic_target().unwrap_or("HEAD");
+                return Ok(SquashOutcome {
+                    kind: OutcomeKind::NoCommits,
+                    branch: refname.trim_start_matches("refs/heads/").to_string(),
+                    head: git2::Oid::zero(),
+                    merge_base: git2::Oid::zero(),
+                    onto: git2::Oid::zero(),
+                    commits: Vec::new(),
+                    tip: None,
+                    backup: None,
+                    wip: false,
+                });
+            }
+            Err(e) => return Err(e.into()),
+        },
+    };
+
+    // The checked out branch can still be checked out in another worktree
+    // too, made with git worktree add --force
+    if squashed_ref.is_none() && opts.new_branch.is_none() && !opts.print_base {
+        if let Some(refname) = repo.find_reference("HEAD")?.symbolic_target() {
+            guard_checked_out(repo, opts, refname)?;
+        }
+    }
+
+    // --new-branch leaves the squashed branch alone and points a new branch
+    // at the squashed commit instead
+    let target_ref = match opts.new_branch {
+        Some(ref name) => Some(new_branch_ref(repo, name, opts.force)?),
+        None => squashed_ref.clone(),
+    };
+
+    let squashed = match squashed_ref {
+        Some(ref name) => repo.find_reference(name)?,
+        None => repo.head()?,
+    };
+    let branch_name = String::from_utf8_lossy(squashed.shorthand_bytes()).into_owned();
+
+    // Only a branch that is rewritten needs protecting, not one the squashed
+    // commit is only copied from
+    if squashed.is_branch() && opts.new_branch.is_none() && !opts.print_base {
+        if let Some(pattern) = protected_by(repo, opts, &branch_name)? {
+            return Err(GitSquashError::Protected(branch_name, pattern));
+        }
+    }
+
+    // --range names both ends, the base is its start whatever the branch
+    // would otherwise be squashed against
+    let range = match opts.range {
+        Some(ref spec) => Some(resolve_range(repo, spec)?),
+        None => None,
+    };
+    if let Some((_, tip)) = range {
+        // Squashing the start of a range into a new branch leaves the
+        // commits after it alone, the branch itself would lose them
+        if tip != head {
+            if opts.new_branch.is_none() {
+                return Err(GitSquashError::RangeNotAtTip(
+                    opts.range.clone().unwrap(),
+                    branch_name,
+                ));
+            }
+            head = tip;
+        }
+    }
+
+    let base = match (&range, &opts.base) {
+        (Some((start, _)), _) => Base {
+            name: opts
+                .range
+                .as_deref()
+                .unwrap()
+                .split("..")
+                .next()
+                .unwrap()
+                .to_string(),
+            oid: *start,
+            source: BaseSource::Argument,
+        },
+        (None, Some(ref name)) => Base {
+            name: name.to_string(),
+            oid: resolve_base(repo, opts, name)?,
+            source: BaseSource::Argument,
+        },
+        (None, None) => match config_base(repo, opts, &squashed)? {
+            Some(base) => base,
+            None => default_base(repo, opts, squashed)?,
+        },
+    };
+    // Not Info, the output of --preview and --print-base is piped
+    match base.source {
+        BaseSource::Argument if !opts.verbose => {}
+        ref source if !opts.stat_only && !opts.quiet => opts.report(
+            Level::Status,
+            &format!("Using {} as the base ({})", base.name, source),
+        ),
+        _ => {}
+    }
+    let branch = base.oid;
+    debug!(
+        "squashing {} at {} onto {} at {} ({})",
+        branch_name, head, base.name, branch, base.source
+    );
+
+    // The merge base and the walk take parents and dates from the
+    // commit-graph when there is one libgit2 can read, instead of parsing
+    // every commit in the object database. Say which in the log to explain
+    // slow walks.
+    match commit_graph(repo) {
+        CommitGraph::File(ref path) => {
+            debug!("walking commits with the commit-graph {}", path.display())
+        }
+        CommitGraph::Unsupported(ref path) => debug!(
+            "the commit-graph {} has chunks libgit2 cannot read, walking commits without it; \
+             `git -c commitGraph.generationVersion=1 commit-graph write --reachable` writes \
+             one it can",
+            path.display()
+        ),
+        CommitGraph::Chain => debug!(
+            "the commit-graph is split, which libgit2 cannot read, walking commits without \
+             it; `git commit-graph write --reachable` without --split writes one it can"
+        ),
+        CommitGraph::Missing => debug!(
+            "the repository has no commit-graph, walking commits without it; \
+             `git commit-graph write --reachable` writes one"
+        ),
+    }
+
+    // When the merge base is the tip, including a branch with only a root
+    // commit squashed against itself, the walk is empty and there is
+    // nothing to squash.
+    let mb = match repo.merge_base(branch, head) {
+        Ok(mb) => mb,
+        Err(ref e) if e.code() == git2::ErrorCode::NotFound => {
+            return Err(GitSquashError::NoMergeBase(branch_name, base.name));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(head)?;
+    revwalk.push(mb)?;
+    revwalk.hide(branch)?;
+    // Parents always come after their children, the commit time only breaks
+    // ties, so dates that are out of order do not shuffle the commits
+    let mut sort = git2::Sort::empty();
+    sort.insert(git2::Sort::TOPOLOGICAL);
+    sort.insert(git2::Sort::TIME);
+    revwalk.set_sorting(sort)?;
+    if opts.first_parent {
+        revwalk.simplify_first_parent()?;
+    }
+
+    // The walk is newest first, so --count only needs its first commits. The
+    // whole range is walked otherwise, --print-base counts it, --since
+    // looks for its cutoff in it and the squash itself lists, checks and
+    // copies from every commit in it.
+    let limit = match opts.count {
+        Some(count) if opts.since.is_none() && !opts.print_base => Some(count),
+        _ => None,
+    };
+    let mut commits_to_squash = Vec::new();
+    for oid in revwalk {
+        if Some(commits_to_squash.len()) == limit {
+            debug!(
+                "stopping the walk after {} commits",
+                commits_to_squash.len()
+            );
+            break;
+        }
+        commits_to_squash.push(oid?);
+        if commits_to_squash.len() % 1000 == 0 {
+            progress.update(&format!("Finding commits: {}", commits_to_squash.len()));
+        }
+    }
+    progress.clear();
+    debug!(
+        "merge base is {}, {} commits on top of it{}",
+        mb,
+        commits_to_squash.len(),
+        if limit.is_some() { " or more" } else { "" }
+    );
+    for oid in &commits_to_squash {
+        trace!("commit in range {}", oid);
+    }
+
+    if opts.print_base {
+        return Ok(SquashOutcome {
+            kind: OutcomeKind::Base,
+            branch: branch_name,
+            head,
+            merge_base: mb,
+            onto: mb,
+            commits: squashed_commits(repo, &commits_to_squash)?,
+            tip: None,
+            backup: None,
+            wip: false,
+        });
+    }
+
+    // The squashed commit goes on top of the merge base unless only the
+    // most recent commits are squashed, then it replaces just those.
+    let mut onto = mb;
+    if let Some(ref spec) = opts.since {
+        let cutoff = date::parse(spec, repo.signature()?.when())
+            .ok_or_else(|| GitSquashError::InvalidDate(spec.to_string()))?;
+        // Like git log --since, stop at the first commit not newer than the
+        // cutoff walking back from the tip, so an older timestamp on a newer
+        // commit ends the window there.
+        let mut cut = head;
+        while commits_to_squash.contains(&cut) {
+            let commit = repo.find_commit(cut)?;
+            if commit.committer().when().seconds() <= cutoff.seconds() {
+                break;
+            }
+            cut = commit.parent_id(0)?;
+        }
+        if commits_to_squash.contains(&cut) {
+            let mut inside = Vec::new();
+            for oid in commits_to_squash {
+                if oid != cut && !repo.graph_descendant_of(cut, oid)? {
+                    inside.push(oid);
+                }
+            }
+            commits_to_squash = inside;
+            onto = cut;
+        }
+        if opts.verbose && !opts.stat_only {
+            let mut listing = format!(
+                "{} commits are newer than {}:",
+                commits_to_squash.len(),
+                spec
+            );
+            for oid in &commits_to_squash {
+                let commit = repo.find_commit(*oid)?;
+                listing.push_str(&format!(
+                    "\n{} {}",
+                    abbrev_id(repo, *oid, opts.abbrev),
+                    summary(&commit)
+                ));
+            }
+            opts.report(Level::Info, &listing);
+        }
+    }
+    if let Some(count) = opts.count {
+        if count > commits_to_squash.len() {
+            return Err(GitSquashError::CountTooLarge(
+                count,
+                commits_to_squash.len(),
+            ));
+        }
+        commits_to_squash.truncate(count);
+        if let Some(oldest) = commits_to_squash.last() {
+            onto = repo.find_commit(*oldest)?.parent_id(0)?;
+        }
+    }
+
+    // Folding the commits after --into into it is squashing them onto its
+    // parent, the commits before it stay as they are
+    if let Some(ref into) = opts.into {
+        let oid = repo.revparse_single(into)?.peel_to_commit()?.id();
+        let position = commits_to_squash
+            .iter()
+            .position(|c| *c == oid)
+            .ok_or_else(|| GitSquashError::NotOnBranch(into.to_string(), branch_name.clone()))?;
+        commits_to_squash.truncate(position + 1);
+        onto = repo.find_commit(oid)?.parent_id(0)?;
+    }
+
+    // --split-at keeps the commits up to it and squashes the ones after it
+    // onto it, at the merge base that is all of them
+    if let Some(ref split_at) = opts.split_at {
+        let oid = repo.revparse_single(split_at)?.peel_to_commit()?.id();
+        if oid != mb {
+            if !commits_to_squash.contains(&oid) {
+                return Err(GitSquashError::NotOnBranch(
+                    split_at.to_string(),
+                    branch_name.clone(),
+                ));
+            }
+            let mut after = Vec::new();
+            for c in commits_to_squash {
+                if repo.graph_descendant_of(c, oid)? {
+                    after.push(c);
+                }
+            }
+            commits_to_squash = after;
+            onto = oid;
+        }
+    }
+
+    // The merge for --onto takes the squashed commits as the only change, the
+    // commits of the branch below them would be lost
+    if opts.onto_base && onto != mb {
+        return Err(GitSquashError::PartialOnto(base.name));
+    }
+
+    let mut outcome = SquashOutcome {
+        kind: OutcomeKind::DryRun,
+        branch: branch_name,
+        head,
+        merge_base: mb,
+        onto,
+        commits: squashed_commits(repo, &commits_to_squash)?,
+        tip: None,
+        backup: None,
+        wip: false,
+    };
+
+    let max_commits = match opts.max_commits {
+        Some(max) => Some(max),
+        None => repo
+            .config()?
+            .get_i64("squash.maxCommits")
+            .ok()
+            .filter(|max| *max > 0)
+            .map(|max| max as usize),
+    };
+    if let Some(max) = max_commits {
+        if commits_to_squash.len() > max {
+            return Err(GitSquashError::TooManyCommits(commits_to_squash.len(), max));
+        }
+    }
+
+    // A single commit is left alone unless its message is to be rewritten,
+    // by --rewrite-single or an option that changes the message
+    let rewrite_single = opts.rewrite_single
+        || opts.message_file.is_some()
+        || opts.reuse_message.is_some()
+        || opts.edit == Some(true)
+        || opts.collect_trailers;
+
+    // A single commit that is exactly what squashing would create counts as
+    // a successful squash, even with --require-squash
+    if opts.idempotent && commits_to_squash.len() == 1 && !rewrite_single {
+        let commit = repo.find_commit(commits_to_squash[0])?;
+        let parent = if opts.onto_base { branch } else { onto };
+        let expected = match opts.path {
+            Some(ref path) => {
+                let base_tree = repo.find_commit(parent)?.tree()?;
+                restrict_to_path(repo, &base_tree, &commit.tree()?, path)?.id()
+            }
+            None => commit.tree_id(),
+        };
+        if commit.parent_count() == 1
+            && commit.parent_id(0)? == parent
+            && commit.tree_id() == expected
+        {
+            outcome.kind = OutcomeKind::AlreadySquashed(commit.id());
+            return Ok(outcome);
+        }
+    }
+
+    if commits_to_squash.len() < 2
+        && !(rewrite_single && commits_to_squash.len() == 1)
+        && opts.require_squash
+    {
+        return Err(GitSquashError::NothingToSquash(commits_to_squash.len()));
+    }
+    if commits_to_squash.is_empty() {
+        // The branch is already part of the base, merged or just behind it
+        outcome.kind = if mb == head {
+            let (_, behind) = repo.graph_ahead_behind(head, branch)?;
+            OutcomeKind::Contained(base.name.clone(), behind)
+        } else {
+            OutcomeKind::NoCommits
+        };
+        return Ok(outcome);
+    } else if commits_to_squash.len() == 1 && !rewrite_single {
+        outcome.kind = OutcomeKind::SingleCommit;
+        return Ok(outcome);
+    }
+
+    if opts.verify_signatures {
+        verify_signatures(repo, opts, &commits_to_squash)?;
+    }
+
+    if opts.autosquash || opts.interactive {
+        let (plan, dropped) = if opts.interactive {
+            interactive_plan(repo, &commits_to_squash, &outcome)?
+        } else {
+            (autosquash_plan(repo, &commits_to_squash)?, Vec::new())
+        };
+        outcome.kind = OutcomeKind::Folded;
+        outcome.commits.retain(|c| {
+            dropped.contains(&c.id) || plan.iter().any(|p| p.folds.iter().any(|f| f.1 == c.id))
+        });
+        if outcome.commits.is_empty() || opts.dry_run {
+            return Ok(outcome);
+        }
+
+        let sig = identity(repo, "COMMITTER")?;
+        let resigned = opts.resign && count_signed(repo, &commits_to_squash)? > 0;
+        let signing = Signing::from_config(opts, &repo.config()?, &sig, resigned);
+        let new_oid = rebuild(
+            repo,
+            opts,
+            &plan,
+            onto,
+            &sig,
+            signing.as_ref(),
+            cleanup_mode(repo, opts),
+        )?;
+        debug!("rebuilt the branch on {} as {}", onto, new_oid);
+        // Folding only moves changes between commits, the branch has to end
+        // up where it was unless commits were dropped
+        if dropped.is_empty()
+            && repo.find_commit(new_oid)?.tree_id() != repo.find_commit(head)?.tree_id()
+        {
+            return Err(GitSquashError::Rewrite(
+                "folding the commits changed the tree of the branch".to_string(),
+            ));
+        }
+
+        let log_message = match dropped.len() {
+            0 => format!("squash: folded {} commits", outcome.commits.len()),
+            n => format!(
+                "squash: folded {} commits and dropped {}",
+                outcome.commits.len() - n,
+                n
+            ),
+        };
+        return finish_squash(
+            repo,
+            opts,
+            target_ref.as_deref(),
+            head,
+            new_oid,
+            &log_message,
+            outcome,
+        );
+    }
+
+    // --reuse-message can take the message from any commit, even one on
+    // another branch
+    let message_oid = match (&opts.reuse_message, &opts.message_from) {
+        (Some(ref rev), _) => repo.revparse_single(rev)?.peel_to_commit()?.id(),
+        (None, MessageSource::Oldest) => oldest_commit(repo, &commits_to_squash, head, onto)?,
+        (None, MessageSource::Newest) => commits_to_squash[0],
+        (None, MessageSource::Rev(ref rev)) => {
+            let oid = repo.revparse_single(rev)?.peel_to_commit()?.id();
+            if !commits_to_squash.contains(&oid) {
+                return Err(GitSquashError::MessageNotInRange(rev.to_string()));
+            }
+            oid
+        }
+    };
+    let message_commit = repo.find_commit(message_oid)?;
+
+    if opts.dry_run {
+        return Ok(outcome);
+    }
+
+    let sig = identity(repo, "COMMITTER")?;
+    // --keep-author reuses the author of the commit the message comes from
+    // as is, name, email, date and its timezone
+    let author = match opts.author {
+        Some(ref ident) => parse_author(ident)?,
+        None if opts.keep_author => message_commit.author().to_owned(),
+        None => identity(repo, "AUTHOR")?,
+    };
+    let author = match opts.date {
+        Some(ref spec) => {
+            let when = date::parse(spec, sig.when())
+                .ok_or_else(|| GitSquashError::InvalidDate(spec.to_string()))?;
+            retimed(&author, &when)?
+        }
+        None => author,
+    };
+
+    // Asked before the editor or the commit-msg hook runs, so refusing
+    // does not throw away an edited message
+    if !opts.allow_foreign_authors && !opts.preview {
+        let authors = foreign_authors(repo, &commits_to_squash, &author)?;
+        if !authors.is_empty() && !confirm_foreign_authors(opts, &authors) {
+            return Err(GitSquashError::ForeignAuthors(authors));
+        }
+    }
+
+    let (message, encoding) =
+        squash_message(repo, opts, &message_commit, &commits_to_squash, &outcome)?;
+    // Like git commit --dry-run, a preview does not run the commit-msg hook,
+    // which may have side effects
+    if opts.preview {
+        outcome.kind = OutcomeKind::Preview(message);
+        return Ok(outcome);
+    }
+    let message = if opts.no_verify {
+        message
+    } else {
+        run_commit_msg_hook(repo, opts, message)?
+    };
+
+    let mut onto_commit = repo.find_commit(onto)?;
+    let resigned = opts.resign && count_signed(repo, &commits_to_squash)? > 0;
+    let signing = Signing::from_config(opts, &repo.config()?, &sig, resigned);
+    let sig = if opts.committer_date_is_author_date {
+        retimed(&sig, &author.when())?
+    } else {
+        sig
+    };
+
+    if !opts.allow_unsigned_squash && signing.is_none() {
+        let signed = count_signed(repo, &commits_to_squash)?;
+        if signed > 0 {
+            opts.report(
+                Level::Warning,
+                &format!(
+                    "{} of {} commits being squashed are signed, the squashed commit will not be",
+                    signed,
+                    commits_to_squash.len()
+                ),
+            );
+            opts.report(
+                Level::Hint,
+                "sign it with --gpg-sign or --resign, or pass --allow-unsigned-squash to silence this warning",
+            );
+        }
+    }
+
+    // A branch that is not checked out, or not moved, is squashed from the
+    // tree of its tip, otherwise the index is used.
+    let tree = match target_ref {
+        Some(_) => repo.find_commit(head)?.tree()?,
+        None => {
+            let mut index = repo.index()?;
+            if with_staged && opts.wip {
+                stage_changes(&mut index, opts.wip_untracked)?;
+                outcome.wip = true;
+            }
+            progress.update("Writing the tree...");
+            let oid = index.write_tree();
+            progress.clear();
+            // The changes --wip staged are written to the index once the
+            // squashed commit is made, until then it is read back as it was
+            // so a squash that fails leaves the index alone
+            if outcome.wip {
+                index.read(true)?;
+            }
+            repo.find_tree(oid?)?
+        }
+    };
+    let mut tree = match opts.path {
+        Some(ref path) => restrict_to_path(repo, &onto_commit.tree()?, &tree, path)?,
+        None => tree,
+    };
+
+    // Apply the combined change to the tip of the base instead, like a
+    // rebase of the squashed commit
+    if opts.onto_base && branch != onto {
+        let base_commit = repo.find_commit(branch)?;
+        let mut index =
+            repo.merge_trees(&onto_commit.tree()?, &base_commit.tree()?, &tree, None)?;
+        if index.has_conflicts() {
+            let mut paths = Vec::new();
+            for conflict in index.conflicts()? {
+                let conflict = conflict?;
+                if let Some(entry) = conflict.our.or(conflict.their).or(conflict.ancestor) {
+                    paths.push(String::from_utf8_lossy(&entry.path).into_owned());
+                }
+            }
+            return Err(GitSquashError::Conflicts(base.name, paths));
+        }
+        tree = repo.find_tree(index.write_tree_to(repo)?)?;
+        onto_commit = base_commit;
+        outcome.onto = branch;
+    }
+
+    // A commit directly on top of the base with the same tree is what the
+    // squash would create, so there is nothing to do.
+    if opts.reuse_existing {
+        for oid in &commits_to_squash {
+            let commit = repo.find_commit(*oid)?;
+            if commit.parent_count() == 1
+                && commit.parent_id(0)? == onto_commit.id()
+                && commit.tree_id() == tree.id()
+            {
+                outcome.kind = OutcomeKind::AlreadySquashed(*oid);
+                return Ok(outcome);
+            }
+        }
+    }
+
+    // Create the commit on top of the merge base
+    let new_oid = create_commit(
+        repo,
+        opts,
+        &author,
+        &sig,
+        &message,
+        encoding.as_deref(),
+        &tree,
+        &[&onto_commit],
+        signing.as_ref(),
+    )?;
+    debug!("created {} on {}", new_oid, onto_commit.id());
+
+    // Squashing the whole branch in place must not change its content.
+    // Staged changes, --path and --onto change it on purpose.
+    if !opts.no_verify_tree && !with_staged && opts.path.is_none() && !opts.onto_base {
+        let expected = repo.find_commit(head)?.tree_id();
+        let actual = repo.find_commit(new_oid)?.tree_id();
+        if actual != expected {
+            if opts.skip_dirty_check {
+                opts.report(
+                    Level::Hint,
+                    "the index has changes that --skip-dirty-check did not look for",
+                );
+            }
+            return Err(GitSquashError::TreeChanged(expected, actual));
+        }
+        if opts.verbose {
+            opts.report(
+                Level::Info,
+                &format!(
+                    "Verified the squashed commit has the tree of the branch, {}",
+                    actual
+                ),
+            );
+        }
+    }
+
+    match copy_notes(repo, opts, &commits_to_squash, new_oid, &sig) {
+        Ok(copied) if opts.verbose && copied > 0 => opts.report(
+            Level::Info,
+            &format!("Copied {} notes to the squashed commit", copied),
+        ),
+        Ok(_) => {}
+        Err(e) => opts.report(
+            Level::Warning,
+            &format!("cannot copy notes to the squashed commit: {}", e),
+        ),
+    }
+
+    let log_message = match commits_to_squash.len() {
+        1 => format!("squash: rewrote {}", short_id(commits_to_squash[0])),
+        n => format!(
+            "squash: squashed {} commits onto {}",
+            n,
+            short_id(onto_commit.id())
+        ),
+    };
+    outcome.kind = OutcomeKind::Squashed;
+    finish_squash(
+        repo,
+        opts,
+        target_ref.as_deref(),
+        head,
+        new_oid,
+        &log_message,
+        outcome,
+    )
+}
+
+// A notes ref as git notes --ref takes it, "review" is refs/notes/review
+fn notes_refname(name: &str) -> String {
+    if name.starts_with("refs/") {
+        name.to_string()
+    } else {
+        format!("refs/notes/{}", name)
+    }
+}
+
+// The notes refs to copy notes in. --copy-notes=<ref> names one, otherwise
+// they are those of notes.rewriteRef, which may be globs, or the default
+// notes ref. Without --copy-notes notes are only copied when
+// notes.rewriteRef is set and notes.rewrite.squash is not false, like
+// git's notes.rewrite.<command>.
+fn notes_refs(repo: &git2::Repository, opts: &SquashOptions) -> Result<Vec<String>, git2::Error> {
+    if let Some(ref name) = opts.notes_ref {
+        return Ok(vec![notes_refname(name)]);
+    }
+
+    let config = repo.config()?;
+    let mut patterns = Vec::new();
+    for entry in &config.entries(Some("^notes\\.rewriteref$"))? {
+        if let Some(value) = entry?.value() {
+            patterns.push(notes_refname(value));
+        }
+    }
+    if !opts.copy_notes
+        && (patterns.is_empty() || !config.get_bool("notes.rewrite.squash").unwrap_or(true))
+    {
+        return Ok(Vec::new());
+    }
+    if patterns.is_empty() {
+        return Ok(vec![repo.note_default_ref()?]);
+    }
+
+    // A ref named by more than one pattern is only copied in once
+    let mut refs: Vec<String> = Vec::new();
+    for pattern in patterns {
+        if !pattern.contains(['*', '?', '[']) {
+            if !refs.contains(&pattern) {
+                refs.push(pattern);
+            }
+            continue;
+        }
+        for reference in repo.references_glob(&pattern)? {
+            if let Some(name) = reference?.name() {
+                if !refs.iter().any(|r| r == name) {
+                    refs.push(name.to_string());
+                }
+            }
+        }
+    }
+    Ok(refs)
+}
+
+// Attach the notes of the squashed commits to the new commit, each notes
+// ref concatenating them oldest first, separated by a blank line like
+// git's notes.rewriteMode concatenate. Returns how many notes were copied.
+fn copy_notes(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+    commits: &[git2::Oid],
+    new_oid: git2::Oid,
+    sig: &git2::Signature,
+) -> Result<usize, git2::Error> {
+    let mut copied = 0;
+    for notes_ref in notes_refs(repo, opts)? {
+        let mut notes = Vec::new();
+        for oid in commits.iter().rev() {
+            let note = match repo.find_note(Some(&notes_ref), *oid) {
+                Ok(note) => note,
+                Err(ref e) if e.code() == git2::ErrorCode::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let text = String::from_utf8_lossy(note.message_bytes());
+            let text = text.trim_end();
+            if !text.is_empty() {
+                notes.push(text.to_string());
+            }
+        }
+        if notes.is_empty() {
+            continue;
+        }
+
+        debug!(
+            "copying {} notes in {} to {}",
+            notes.len(),
+            notes_ref,
+            new_oid
+        );
+        let message = notes.join("\n\n") + "\n";
+        repo.note(sig, sig, Some(&notes_ref), new_oid, &message, true)?;
+        copied += notes.len();
+    }
+    Ok(copied)
+}
+
+// Move the squashed branch to the new commit and run the --post-squash
+// command, rolling back if asked to when it fails
+fn finish_squash(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+    squashed_ref: Option<&str>,
+    old_oid: git2::Oid,
+    new_oid: git2::Oid,
+    log_message: &str,
+    mut outcome: SquashOutcome,
+) -> Result<SquashOutcome, GitSquashError> {
+    let log_message = opts.reflog_message.as_deref().unwrap_or(log_message);
+    let advance = match opts.advance {
+        Some(ref name) => Some(advance_target(repo, name, new_oid, opts.force)?),
+        None => None,
+    };
+
+    // With --onto, or commits dropped with --interactive, the new tip has a
+    // different tree than the working copy, which has to follow it
+    let checkout = |oid: git2::Oid| -> Result<(), git2::Error> {
+        if (opts.onto_base || opts.interactive) && squashed_ref.is_none() {
+            let mut builder = git2::build::CheckoutBuilder::new();
+            builder.safe();
+            repo.checkout_tree(repo.find_commit(oid)?.as_object(), Some(&mut builder))?;
+        }
+        Ok(())
+    };
+
+    // What to roll back to, a new branch is deleted again
+    let previous = match squashed_ref {
+        Some(refname) => repo.refname_to_id(refname).ok(),
+        None => Some(old_oid),
+    };
+
+    if !opts.no_backup {
+        if let Some(old) = previous {
+            outcome.backup = backup_tip(repo, squashed_ref, old)?;
+        }
+    }
+
+    if outcome.wip {
+        let mut index = repo.index()?;
+        stage_changes(&mut index, opts.wip_untracked)?;
+        index.write()?;
+    }
+    checkout(new_oid)?;
+    move_squashed(repo, squashed_ref, new_oid, log_message)?;
+    outcome.tip = Some(new_oid);
+
+    // Only HEAD's branch has a working copy that should match
+    if opts.verify_clean_after
+        && squashed_ref.is_none()
+        && is_dirty(&worktree_status(repo, opts)?, opts.strict)
+    {
+        return Err(GitSquashError::DirtyAfterSquash);
+    }
+
+    if let Some(ref command) = opts.post_squash {
+        let success = run_post_squash(repo, opts, command, old_oid, new_oid, &outcome)?;
+        if !success {
+            if opts.post_squash_rollback {
+                match previous {
+                    Some(oid) => {
+                        checkout(oid)?;
+                        move_squashed(
+                            repo,
+                            squashed_ref,
+                            oid,
+                            "squash (rollback): the post-squash command failed",
+                        )?;
+                    }
+                    None => {
+                        debug!("deleting {}", squashed_ref.unwrap());
+                        repo.find_reference(squashed_ref.unwrap())?.delete()?
+                    }
+                }
+                // Nothing is left to undo
+                if let Some(ref backup) = outcome.backup.take() {
+                    repo.find_reference(backup)?.delete()?;
+                }
+            }
+            return Err(GitSquashError::PostSquash(
+                command.to_string(),
+                opts.post_squash_rollback,
+            ));
+        }
+    }
+
+    if let Some((refname, old)) = advance {
+        debug!("advancing {} from {} to {}", refname, old, new_oid);
+        repo.reference_matching(
+            &refname,
+            new_oid,
+            true,
+            old,
+            "squash: advanced to the squashed commit",
+        )?;
+        if !opts.quiet {
+            opts.report(
+                Level::Info,
+                &format!(
+                    "Advanced {} to {}",
+                    refname.trim_start_matches("refs/heads/"),
+                    short_id(new_oid)
+                ),
+            );
+        }
+    }
+
+    if opts.push {
+        push(repo, opts, squashed_ref, old_oid).map_err(|e| match e {
+            GitSquashError::Push(_) => e,
+            e => GitSquashError::Push(e.to_string()),
+        })?;
+    }
+
+    Ok(outcome)
+}
+
+// Credentials for talking to a remote from ssh-agent, git's credential
+// helpers or the default for the platform, each tried once
+fn remote_callbacks(config: &git2::Config) -> git2::RemoteCallbacks<'_> {
+    let mut tried = git2::CredentialType::empty();
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(move |url, username, allowed| {
+        let untried = allowed - tried;
+        if untried.contains(git2::CredentialType::USERNAME) {
+            tried.insert(git2::CredentialType::USERNAME);
+            git2::Cred::username(username.unwrap_or("git"))
+        } else if untried.contains(git2::CredentialType::SSH_KEY) {
+            tried.insert(git2::CredentialType::SSH_KEY);
+            git2::Cred::ssh_key_from_agent(username.unwrap_or("git"))
+        } else if untried.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
+            tried.insert(git2::CredentialType::USER_PASS_PLAINTEXT);
+            git2::Cred::credential_helper(config, url, username)
+        } else if untried.contains(git2::CredentialType::DEFAULT) {
+            tried.insert(git2::CredentialType::DEFAULT);
+            git2::Cred::default()
+        } else {
+            Err(git2::Error::from_str("no credentials were accepted"))
+        }
+    });
+    callbacks
+}
+
+// Force push the squashed branch to its upstream, or the same name on
+// --remote, but only if the remote branch is still where the branch was
+// before the squash or where it was last fetched, like git push
+// --force-with-lease. The push goes over the connection the remote branch
+// was checked on and sends the value listed there as the one it replaces,
+// so the remote refuses it when the branch moved in between.
+fn push(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+    squashed_ref: Option<&str>,
+    old_oid: git2::Oid,
+) -> Result<(), GitSquashError> {
+    let refname = match squashed_ref {
+        Some(refname) => refname.to_string(),
+        None => {
+            let head = repo.head()?;
+            if !head.is_branch() {
+                return Err(GitSquashError::Push("HEAD is not on a branch".to_string()));
+            }
+            String::from_utf8_lossy(head.name_bytes()).into_owned()
+        }
+    };
+    let short = refname.trim_start_matches("refs/heads/");
+    let config = repo.config()?;
+
+    let remote_name = match opts.remote {
+        Some(ref remote) => remote.to_string(),
+        None => match repo.branch_upstream_remote(&refname) {
+            Ok(remote) => remote.as_str().unwrap_or("").to_string(),
+            Err(_) => {
+                return Err(GitSquashError::Push(format!(
+                    "{} has no upstream, pass --remote to choose where to push",
+                    short
+                )))
+            }
+        },
+    };
+    let dest = match opts.remote {
+        Some(_) => refname.clone(),
+        None => config.get_string(&format!("branch.{}.merge", short))?,
+    };
+    let tracking = format!(
+        "refs/remotes/{}/{}",
+        remote_name,
+        dest.trim_start_matches("refs/heads/")
+    );
+    let fetched = repo.refname_to_id(&tracking).ok();
+    let mut remote = repo.find_remote(&remote_name)?;
+
+    let mut rejected = None;
+    {
+        let mut connection =
+            remote.connect_auth(git2::Direction::Push, Some(remote_callbacks(&config)), None)?;
+        let current = connection
+            .list()?
+            .iter()
+            .find(|h| h.name() == dest)
+            .map(|h| h.oid());
+        if let Some(current) = current {
+            if current != old_oid && Some(current) != fetched {
+                return Err(GitSquashError::Push(format!(
+                    "{} on {} is at {} which was not fetched, the remote has moved since the branch was last pushed",
+                    dest,
+                    remote_name,
+                    short_id(current)
+                )));
+            }
+        }
+
+        let mut callbacks = remote_callbacks(&config);
+        callbacks.push_update_reference(|name, status| {
+            if let Some(status) = status {
+                rejected = Some(format!("{} was rejected: {}", name, status));
+            }
+            Ok(())
+        });
+        let mut push_options = git2::PushOptions::new();
+        push_options.remote_callbacks(callbacks);
+        connection
+            .remote()
+            .push(&[format!("+{}:{}", refname, dest)], Some(&mut push_options))?;
+    }
+    if let Some(rejected) = rejected {
+        return Err(GitSquashError::Push(rejected));
+    }
+
+    debug!("pushed {} to {} on {}", refname, dest, remote_name);
+    if !opts.quiet {
+        opts.report(Level::Info, &format!("Pushed {} to {}", short, remote_name));
+    }
+    Ok(())
+}
+
+// The reference for --new-branch, which may only replace an existing branch
+// with --force
+fn new_branch_ref(
+    repo: &git2::Repository,
+    name: &str,
+    force: bool,
+) -> Result<String, GitSquashError> {
+    let refname = format!("refs/heads/{}", name);
+    if !git2::Reference::is_valid_name(&refname) {
+        return Err(
+            git2::Error::from_str(&format!("'{}' is not a valid branch name", name)).into(),
+        );
+    }
+
+    if let Ok(branch) = repo.find_branch(name, git2::BranchType::Local) {
+        if !force {
+            return Err(GitSquashError::BranchExists(name.to_string()));
+        }
+        if branch.is_head() {
+            return Err(git2::Error::from_str("Cannot force update the current branch").into());
+        }
+        if let Some(path) = checked_out_elsewhere(repo, &refname) {
+            return Err(GitSquashError::CheckedOutElsewhere(name.to_string(), path));
+        }
+    }
+
+    Ok(refname)
+}
+
+// The reference of the --advance branch and where it is, checked before
+// anything changes. It has to fast-forward to the squashed commit unless
+// forced, and not be checked out where it would leave a working copy
+// behind.
+fn advance_target(
+    repo: &git2::Repository,
+    name: &str,
+    new_oid: git2::Oid,
+    force: bool,
+) -> Result<(String, git2::Oid), GitSquashError> {
+    let branch = local_branch(repo, name)?;
+    let refname = String::from_utf8_lossy(branch.get().name_bytes()).into_owned();
+    let old = branch
+        .get()
+        .target()
+        .ok_or_else(|| GitSquashError::SymbolicRef(refname.clone()))?;
+    if branch.is_head() {
+        return Err(
+            git2::Error::from_str(&format!("Cannot advance {}, it is checked out", name)).into(),
+        );
+    }
+    if let Some(path) = checked_out_elsewhere(repo, &refname) {
+        return Err(git2::Error::from_str(&format!(
+            "Cannot advance {}, it is checked out in the worktree at {}",
+            name,
+            path.display()
+        ))
+        .into());
+    }
+    if !force && old != new_oid && !repo.graph_descendant_of(new_oid, old)? {
+        return Err(GitSquashError::NotFastForward(name.to_string()));
+    }
+    Ok((refname, old))
+}
+
+// Backups of the tips of squashed branches, one for each branch that is
+// replaced by the next squash of it
+const BACKUP_REFS: &str = "refs/squash-backup/";
+
+// Point the backup ref of the squashed branch at its tip before the squash.
+// A detached HEAD has no branch to name the backup after.
+fn backup_tip(
+    repo: &git2::Repository,
+    squashed_ref: Option<&str>,
+    old_oid: git2::Oid,
+) -> Result<Option<String>, git2::Error> {
+    let head;
+    let refname = match squashed_ref {
+        Some(refname) => refname,
+        None => {
+            head = repo.find_reference("HEAD")?;
+            match head.symbolic_target() {
+                Some(refname) => refname,
+                None => return Ok(None),
+            }
+        }
+    };
+    let branch = match refname.strip_prefix("refs/heads/") {
+        Some(branch) => branch,
+        None => return Ok(None),
+    };
+
+    let backup = format!("{}{}", BACKUP_REFS, branch);
+    debug!("backing up {} at {} to {}", refname, old_oid, backup);
+    repo.reference(
+        &backup,
+        old_oid,
+        true,
+        "squash: backup of the tip before the squash",
+    )?;
+    Ok(Some(backup))
+}
+
+// Delete the backups that are no longer needed, those of branches that
+// contain them again, like after an undo. Backups of deleted branches are
+// kept. Returns the deleted refs and where they pointed.
+pub fn prune_backups(repo: &git2::Repository) -> Result<Vec<(String, git2::Oid)>, GitSquashError> {
+    let mut pruned = Vec::new();
+    for reference in repo.references_glob(&format!("{}*", BACKUP_REFS))? {
+        let mut reference = reference?;
+        let refname = String::from_utf8_lossy(reference.name_bytes()).into_owned();
+        let backup = match reference.target() {
+            Some(oid) => oid,
+            None => continue,
+        };
+        let branch = format!("refs/heads/{}", &refname[BACKUP_REFS.len()..]);
+        let tip = match repo.refname_to_id(&branch) {
+            Ok(tip) => tip,
+            Err(_) => continue,
+        };
+        if tip == backup || repo.graph_descendant_of(tip, backup)? {
+            debug!("deleting {}, {} contains {}", refname, branch, backup);
+            reference.delete()?;
+            pruned.push((refname, backup));
+        }
+    }
+    Ok(pruned)
+}
+
+// Move the squashed branch, or HEAD when it was not named, to a commit
+fn move_squashed(
+    repo: &git2::Repository,
+    squashed_ref: Option<&str>,
+    oid: git2::Oid,
+    log_message: &str,
+) -> Result<(), git2::Error> {
+    debug!("moving {} to {}", squashed_ref.unwrap_or("HEAD"), oid);
+    match squashed_ref {
+        Some(refname) => repo.reference(refname, oid, true, log_message).map(|_| ()),
+        None => update_head(repo, oid, log_message),
+    }
+}
+
+// Where hooks are looked up, core.hooksPath like git does, relative to the
+// working tree or to the repository when it is bare, and otherwise the hooks
+// directory shared by all worktrees
+fn hooks_dir(repo: &git2::Repository) -> PathBuf {
+    match repo.config().and_then(|c| c.get_path("core.hooksPath")) {
+        Ok(path) => repo.workdir().unwrap_or_else(|| repo.path()).join(path),
+        Err(_) => common_dir(repo).join("hooks"),
+    }
+}
+
+// The hook with this name if there is one git would run, one that is not
+// executable is ignored with a hint like git gives
+fn find_hook(repo: &git2::Repository, opts: &SquashOptions, name: &str) -> Option<PathBuf> {
+    let path = hooks_dir(repo).join(name);
+    let metadata = fs::metadata(&path).ok().filter(|m| m.is_file())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 == 0 {
+            opts.report(
+                Level::Hint,
+                &format!(
+                    "the '{}' hook was ignored because it's not set as executable",
+                    path.display()
+                ),
+            );
+            return None;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = metadata;
+    Some(path)
+}
+
+// Run the commit-msg hook on the squashed message like git commit does,
+// returning the message as the hook left it
+fn run_commit_msg_hook(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+    message: Vec<u8>,
+) -> Result<Vec<u8>, GitSquashError> {
+    let hook = match find_hook(repo, opts, "commit-msg") {
+        Some(hook) => hook,
+        None => return Ok(message),
+    };
+    debug!("running {}", hook.display());
+
+    let path = repo.path().join("SQUASH_EDITMSG");
+    let hook_error = |e: io::Error| {
+        opts.report(
+            Level::Error,
+            &format!("cannot run {}: {}", hook.display(), e),
+        );
+        GitSquashError::HookFailed("commit-msg".to_string())
+    };
+    fs::write(&path, &message).map_err(hook_error)?;
+    let status = process::Command::new(&hook)
+        .arg(&path)
+        .current_dir(repo.workdir().unwrap_or_else(|| repo.path()))
+        .status()
+        .map_err(hook_error)?;
+    if !status.success() {
+        return Err(GitSquashError::HookFailed("commit-msg".to_string()));
+    }
+    fs::read(&path).map_err(hook_error)
+}
+
+// Run the --post-squash command in the working copy, describing the squash
+// in its environment. Returns whether it succeeded.
+fn run_post_squash(
+    repo: &git2::Repository,
+    opts: &SquashOptions,
+    command: &str,
+    old_oid: git2::Oid,
+    new_oid: git2::Oid,
+    outcome: &SquashOutcome,
+) -> Result<bool, GitSquashError> {
+    let dir = repo.workdir().unwrap_or_else(|| repo.path());
+    let status = process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(dir)
+        .env("GIT_SQUASH_OLD_OID", old_oid.to_string())
+        .env("GIT_SQUASH_NEW_OID", new_oid.to_string())
+        .env("GIT_SQUASH_BASE", outcome.onto.to_string())
+        .env("GIT_SQUASH_BRANCH", &outcome.branch)
+        .status();
+
+    match status {
+        Ok(status) => Ok(status.success()),
+        Err(e) => {
+            opts.report(
+                Level::Error,
+                &format!("cannot run post-squash command: {}", e),
+            );
+            Ok(false)
+        }
+    }
+}
+
+// A local branch by name, failing with an error that names it when there
+// is none rather than the message from libgit2
+fn local_branch<'r>(
+    repo: &'r git2::Repository,
+    name: &str,
+) -> Result<git2::Branch<'r>, GitSquashError> {
+    repo.find_branch(name, git2::BranchType::Local)
+        .map_err(|e| match e.code() {
+            git2::ErrorCode::NotFound => GitSquashError::NotALocalBranch(name.to_string()),
+            _ => e.into(),
+        })
+}
+
+// Put a branch back where it was before the last squash, its backup ref or
+// else the previous entry of its reflog. The working copy of a checked out
+// branch follows it.
+// Returns the branch and the commit it was restored to.
+pub fn undo(
+    repo: &git2::Repository,
+    branch: Option<&str>,
+) -> Result<(String, git2::Oid), GitSquashError> {
+    let branch = match branch {
+        Some(name) => local_branch(repo, name)?,
+        None => {
+            let head = repo.head()?;
+            if !head.is_branch() {
+                return Err(GitSquashError::SymbolicRef("HEAD".to_string()));
+            }
+            git2::Branch::wrap(head)
+        }
+    };
+    let name = String::from_utf8_lossy(branch.name_bytes()?).into_owned();
+    let refname = String::from_utf8_lossy(branch.get().name_bytes()).into_owned();
+
+    // Only the squash itself is undone, the tip has to be the one it made.
+    // A squash logs "squash: ...", or the --reflog-message, moving the branch
+    // from the tip its backup ref names. A rollback or an undo logs
+    // "squash (rollback): ..." or "squash (undo): ..." and is not a squash to
+    // undo, nor is a commit made after the squash.
+    let backup = format!("{}{}", BACKUP_REFS, name);
+    let backup_oid = repo.refname_to_id(&backup).ok();
+    let tip = branch
+        .get()
+        .target()
+        .ok_or_else(|| GitSquashError::SymbolicRef(refname.clone()))?;
+    let reflog = repo.reflog(&refname)?;
+    let previous = match reflog.get(0) {
+        Some(ref entry)
+            if entry.id_new() == tip
+                && (entry
+                    .message_bytes()
+                    .is_some_and(|m| m.starts_with(b"squash:"))
+                    || Some(entry.id_old()) == backup_oid) =>
+        {
+            // The backup names the tip before the squash whatever the reflog
+            // entry says
+            backup_oid.unwrap_or_else(|| entry.id_old())
+        }
+        _ => return Err(GitSquashError::NotSquashed(name)),
+    };
+
+    if branch.is_head() {
+        let mut builder = git2::build::CheckoutBuilder::new();
+        builder.safe();
+        repo.checkout_tree(repo.find_commit(previous)?.as_object(), Some(&mut builder))?;
+    }
+    repo.reference(
+        &refname,
+        previous,
+        true,
+        "squash (undo): returning to the tip before the squash",
+    )?;
+    // The backup is used up, a later undo must not go back to it again
+    if let Ok(mut reference) = repo.find_reference(&backup) {
+        reference.delete()?;
+    }
+
+    Ok((name, previous))
+}
+
+// Record the base to squash the current branch, or the one given with
+// --branch, on to.
+pub fn set_base(opts: &SquashOptions, base: &str) -> Result<(), GitSquashError> {
+    let repo = open_repo(opts)?;
+
+    let branch = match opts.branch {
+        Some(ref name) => local_branch(&repo, name)?,
+        None => {
+            let head = repo.head()?;
+            if !head.is_branch() {
+                return Err(GitSquashError::SymbolicRef("HEAD".to_string()));
+            }
+            git2::Branch::wrap(head)
+        }
+    };
+    let name = String::from_utf8_lossy(branch.name_bytes()?).into_owned();
+
+    // Fail now rather than on the next squash
+    resolve_base(&repo, opts, base)?;
+
+    repo.config()?.set_str(&branch_base_key(&name), base)?;
+    opts.report(
+        Level::Info,
+        &format!("Set the squash base of {} to {}", name, base),
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::Mutex;
+
+    // The squash reads GIT_AUTHOR_* and GIT_COMMITTER_*, which some tests
+    // set, so the tests that squash take turns
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn env_lock() -> std::sync::MutexGuard<'static, ()> {
+        ENV.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // A repository in a temporary directory, removed again when dropped
+    struct TestRepo {
+        dir: PathBuf,
+        repo: git2::Repository,
+    }
+
+    impl TestRepo {
+        fn new(name: &str) -> TestRepo {
+            let dir = env::temp_dir().join(format!("git-squash-test-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            let repo = git2::Repository::init(&dir).unwrap();
+            let mut config = repo.config().unwrap();
+            config.set_str("user.name", "Tester").unwrap();
+            config.set_str("user.email", "tester@example.com").unwrap();
+            TestRepo {
+                dir: canonical(&dir),
+                repo,
+            }
+        }
+
+        // master with two commits and feature with three more on top,
+        // checked out
+        fn branched(name: &str) -> TestRepo {
+            let test = TestRepo::new(name);
+            test.commit("base", "1\n", "base 1", 1000);
+            test.commit("base", "2\n", "base 2", 2000);
+            test.branch("feature");
+            test.checkout("feature");
+            test.commit("f1", "1\n", "feature 1", 3000);
+            test.commit("f2", "2\n", "feature 2", 4000);
+            test.commit("f3", "3\n", "feature 3", 5000);
+            test
+        }
+
+        fn signature(time: i64) -> git2::Signature<'static> {
+            git2::Signature::new("Tester", "tester@example.com", &git2::Time::new(time, 0)).unwrap()
+        }
+
+        // Commit a file on the checked out branch through the index, so the
+        // working copy stays clean
+        fn commit(&self, path: &str, content: &str, message: &str, time: i64) -> git2::Oid {
+            let sig = TestRepo::signature(time);
+            self.commit_as(path, content, message, &sig, &sig)
+        }
+
+        fn commit_as(
+            &self,
+            path: &str,
+            content: &str,
+            message: &str,
+            author: &git2::Signature,
+            committer: &git2::Signature,
+        ) -> git2::Oid {
+            let workdir = self.repo.workdir().unwrap();
+            fs::write(workdir.join(path), content).unwrap();
+            let mut index = self.repo.index().unwrap();
+            index.add_path(Path::new(path)).unwrap();
+            index.write().unwrap();
+            let tree = self.repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let parents: Vec<git2::Commit> = self
+                .repo
+                .head()
+                .and_then(|h| h.peel_to_commit())
+                .into_iter()
+                .collect();
+            let parents: Vec<&git2::Commit> = parents.iter().collect();
+            self.repo
+                .commit(Some("HEAD"), author, committer, message, &tree, &parents)
+                .unwrap()
+        }
+
+        fn head(&self) -> git2::Oid {
+            self.repo.refname_to_id("HEAD").unwrap()
+        }
+
+        fn branch_tip(&self, name: &str) -> git2::Oid {
+            self.repo
+                .refname_to_id(&format!("refs/heads/{}", name))
+                .unwrap()
+        }
+
+        fn branch(&self, name: &str) {
+            let head = self.repo.head().unwrap().peel_to_commit().unwrap();
+            self.repo.branch(name, &head, false).unwrap();
+        }
+
+        fn checkout(&self, name: &str) {
+            self.repo.set_head(&format!("refs/heads/{}", name)).unwrap();
+            let mut builder = git2::build::CheckoutBuilder::new();
+            builder.force();
+            self.repo.checkout_head(Some(&mut builder)).unwrap();
+        }
+
+        // A linked worktree with the branch checked out, laid out like git
+        // worktree add --force makes it
+        fn add_worktree(&self, name: &str, branch: &str) -> PathBuf {
+            let path = canonical(&self.dir).with_file_name(format!(
+                "{}-{}",
+                self.dir.file_name().unwrap().to_string_lossy(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            let admin = self.repo.path().join("worktrees").join(name);
+            fs::create_dir_all(&admin).unwrap();
+            fs::write(admin.join("HEAD"), format!("ref: refs/heads/{}\n", branch)).unwrap();
+            fs::write(admin.join("commondir"), "../..\n").unwrap();
+            fs::write(
+                admin.join("gitdir"),
+                format!("{}\n", path.join(".git").display()),
+            )
+            .unwrap();
+            fs::write(path.join(".git"), format!("gitdir: {}\n", admin.display())).unwrap();
+
+            let worktree = git2::Repository::open(&path).unwrap();
+            let mut builder = git2::build::CheckoutBuilder::new();
+            builder.force();
+            worktree.checkout_head(Some(&mut builder)).unwrap();
+            path
+        }
+
+        fn opts(&self, base: &str) -> SquashOptions {
+            SquashOptions {
+                dir: self.dir.clone(),
+                base: Some(base.to_string()),
+                quiet: true,
+                ..Default::default()
+            }
+        }
+    }
+
+    impl Drop for TestRepo {
+        fn drop(&mut self) {
+            if let Ok(entries) = fs::read_dir(self.repo.path().join("worktrees")) {
+                for entry in entries.flatten() {
+                    if let Ok(gitdir) = fs::read_to_string(entry.path().join("gitdir")) {
+                        if let Some(path) = Path::new(gitdir.trim()).parent() {
+                            let _ = fs::remove_dir_all(path);
+                        }
+                    }
+                }
+            }
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    // Keeps what a squash reports to look at afterwards
+    #[derive(Clone, Default)]
+    struct Recorder(Rc<RefCell<Vec<(Level, String)>>>);
+
+    impl Recorder {
+        fn messages(&self) -> Vec<(Level, String)> {
+            self.0.borrow().clone()
+        }
+    }
+
+    impl Reporter for Recorder {
+        fn report(&self, level: Level, message: &str) {
+            self.0.borrow_mut().push((level, message.to_string()));
+        }
+    }
+
+    #[test]
+    fn error_source_is_the_git2_error() {
+        use std::error::Error;
+
+        let err = GitSquashError::from(git2::Error::from_str("bad object"));
+        let source = err.source().expect("a git2 error has a source");
+        let git2_err = source
+            .downcast_ref::<git2::Error>()
+            .expect("the source is the git2::Error");
+        assert_eq!(git2_err.message(), "bad object");
+
+        assert!(GitSquashError::NoBase.source().is_none());
+    }
+
+    #[test]
+    fn cleanup_default_keeps_unedited_comments() {
+        let message = "Subject  \n\n\n# not a comment here\nBody\n\n";
+        assert_eq!(
+            cleanup_message(message, Cleanup::Default, false, '#'),
+            "Subject\n\n# not a comment here\nBody\n"
+        );
+    }
+
+    #[test]
+    fn cleanup_default_strips_edited_comments() {
+        let message = "\nSubject\n# Please enter the message\n\nBody\n#\n";
+        assert_eq!(
+            cleanup_message(message, Cleanup::Default, true, '#'),
+            "Subject\n\nBody\n"
+        );
+    }
+
+    #[test]
+    fn cleanup_strip_and_whitespace() {
+        let message = "Subject\t\n; comment\n\n\n\nBody  \n";
+        assert_eq!(
+            cleanup_message(message, Cleanup::Strip, false, ';'),
+            "Subject\n\nBody\n"
+        );
+        assert_eq!(
+            cleanup_message(message, Cleanup::Whitespace, true, ';'),
+            "Subject\n; comment\n\nBody\n"
+        );
+    }
+
+    #[test]
+    fn cleanup_verbatim() {
+        let message = "Subject  \n\n\n# kept\n\n";
+        assert_eq!(
+            cleanup_message(message, Cleanup::Verbatim, true, '#'),
+            message
+        );
+    }
+
+    #[test]
+    fn cleanup_scissors() {
+        let message = format!("Subject\n\n# kept\n# {}\ndiff --git a/x b/x\n", SCISSORS);
+        assert_eq!(
+            cleanup_message(&message, Cleanup::Scissors, true, '#'),
+            "Subject\n\n# kept\n"
+        );
+        // Only an edited message is cut
+        assert_eq!(
+            cleanup_message(&message, Cleanup::Scissors, false, '#'),
+            message
+        );
+    }
+
+    #[test]
+    fn cleanup_to_nothing() {
+        assert_eq!(
+            cleanup_message("# only\n# comments\n\n", Cleanup::Strip, false, '#'),
+            ""
+        );
+    }
+
+    #[test]
+    fn scissors_cut_the_diff() {
+        let message = format!(
+            "Subject\n\nBody\n# {}\n# Do not modify or remove the line above.\ndiff\n",
+            SCISSORS
+        );
+        assert_eq!(cut_at_scissors(&message, '#'), "Subject\n\nBody\n");
+    }
+
+    #[test]
+    fn scissors_with_another_comment_char() {
+        let message = format!("Subject\n# {}\n; {}\ndiff\n", SCISSORS, SCISSORS);
+        assert_eq!(
+            cut_at_scissors(&message, ';'),
+            format!("Subject\n# {}\n", SCISSORS)
+        );
+    }
+
+    #[test]
+    fn scissors_missing_or_altered() {
+        assert_eq!(cut_at_scissors("Subject\n", '#'), "Subject\n");
+        let altered = format!("Subject\n #{}\nmore\n", SCISSORS);
+        assert_eq!(cut_at_scissors(&altered, '#'), altered);
+        // At the very top the whole message is cut
+        let top = format!("# {}\ndiff\n", SCISSORS);
+        assert_eq!(cut_at_scissors(&top, '#'), "");
+    }
+
+    #[test]
+    fn fold_kinds() {
+        assert!(matches!(
+            fold_kind("fixup! Add x"),
+            Some((Fold::Fixup, "Add x"))
+        ));
+        assert!(matches!(
+            fold_kind("squash! Add x"),
+            Some((Fold::Squash, "Add x"))
+        ));
+        assert!(matches!(
+            fold_kind("amend! Add x"),
+            Some((Fold::Amend, "Add x"))
+        ));
+    }
+
+    #[test]
+    fn fold_kind_of_nested_prefixes() {
+        // The outermost prefix decides how it is folded
+        assert!(matches!(
+            fold_kind("fixup! squash! amend! Add x"),
+            Some((Fold::Fixup, "Add x"))
+        ));
+        assert!(matches!(
+            fold_kind("squash! fixup! Add x"),
+            Some((Fold::Squash, "Add x"))
+        ));
+    }
+
+    #[test]
+    fn fold_kind_of_other_subjects() {
+        assert!(fold_kind("Add x").is_none());
+        assert!(fold_kind("fixup!Add x").is_none());
+        assert!(fold_kind("Fixup! Add x").is_none());
+        assert!(fold_kind("Revert \"fixup! Add x\"").is_none());
+    }
+
+    #[test]
+    fn base_with_slashes_is_a_local_branch_first() {
+        let test = TestRepo::branched("base-slashes");
+        let master = test.repo.refname_to_id("refs/heads/master").unwrap();
+        let feature = test.head();
+        test.repo
+            .reference("refs/heads/release/2024.06", master, false, "")
+            .unwrap();
+        test.repo
+            .reference("refs/remotes/origin/release/2024.06", feature, false, "")
+            .unwrap();
+
+        assert_eq!(
+            resolve_base(&test.repo, &SquashOptions::default(), "release/2024.06").unwrap(),
+            master
+        );
+        assert_eq!(
+            resolve_base(
+                &test.repo,
+                &SquashOptions::default(),
+                "origin/release/2024.06"
+            )
+            .unwrap(),
+            feature
+        );
+    }
+
+    #[test]
+    fn base_ambiguous_between_local_and_remote() {
+        let test = TestRepo::branched("base-ambiguous");
+        let master = test.repo.refname_to_id("refs/heads/master").unwrap();
+        let feature = test.head();
+        // A local branch with a slash named like a remote-tracking one
+        test.repo
+            .reference("refs/heads/origin/main", master, false, "")
+            .unwrap();
+        test.repo
+            .reference("refs/remotes/origin/main", feature, false, "")
+            .unwrap();
+
+        assert_eq!(
+            resolve_base(&test.repo, &SquashOptions::default(), "origin/main").unwrap(),
+            master
+        );
+        assert_eq!(
+            resolve_base(
+                &test.repo,
+                &SquashOptions::default(),
+                "refs/remotes/origin/main"
+            )
+            .unwrap(),
+            feature
+        );
+        assert_eq!(
+            resolve_base(
+                &test.repo,
+                &SquashOptions::default(),
+                "refs/heads/origin/main"
+            )
+            .unwrap(),
+            master
+        );
+    }
+
+    #[test]
+    fn base_unknown() {
+        let test = TestRepo::branched("base-unknown");
+        assert!(matches!(
+            resolve_base(&test.repo, &SquashOptions::default(), "team/nope"),
+            Err(GitSquashError::UnknownBase(_))
+        ));
+    }
+
+    #[test]
+    fn worktree_checked_out_elsewhere() {
+        let test = TestRepo::branched("worktree-elsewhere");
+        let path = test.add_worktree("second", "master");
+        assert_eq!(
+            checked_out_elsewhere(&test.repo, "refs/heads/master"),
+            Some(path.clone())
+        );
+        assert_eq!(
+            checked_out_elsewhere(&test.repo, "refs/heads/feature"),
+            None
+        );
+
+        let worktree = git2::Repository::open(&path).unwrap();
+        assert_eq!(
+            checked_out_elsewhere(&worktree, "refs/heads/feature"),
+            Some(test.dir.clone())
+        );
+        assert_eq!(checked_out_elsewhere(&worktree, "refs/heads/master"), None);
+    }
+
+    #[test]
+    fn worktree_stale_is_ignored() {
+        let test = TestRepo::branched("worktree-stale");
+        let path = test.add_worktree("gone", "master");
+        fs::remove_dir_all(&path).unwrap();
+        assert_eq!(checked_out_elsewhere(&test.repo, "refs/heads/master"), None);
+    }
+
+    #[test]
+    fn worktree_refuses_head_checked_out_twice() {
+        let _env = env_lock();
+        let test = TestRepo::branched("worktree-twice");
+        let head = test.head();
+        let path = test.add_worktree("second", "feature");
+
+        match squash(&test.repo, &test.opts("master")) {
+            Err(GitSquashError::CheckedOutElsewhere(branch, at)) => {
+                assert_eq!(branch, "feature");
+                assert_eq!(at, path);
+            }
+            _ => panic!("squashed a branch checked out in another worktree"),
+        }
+        assert_eq!(test.head(), head);
+
+        let opts = SquashOptions {
+            force: true,
+            ..test.opts("master")
+        };
+        squash(&test.repo, &opts).unwrap();
+        assert_ne!(test.head(), head);
+    }
+
+    #[test]
+    fn worktree_refuses_other_branch_checked_out_elsewhere() {
+        let _env = env_lock();
+        let test = TestRepo::branched("worktree-other");
+        test.branch("other");
+        let path = test.add_worktree("second", "other");
+
+        let opts = SquashOptions {
+            branch: Some("other".to_string()),
+            ..test.opts("master")
+        };
+        match squash(&test.repo, &opts) {
+            Err(GitSquashError::CheckedOutElsewhere(branch, at)) => {
+                assert_eq!(branch, "other");
+                assert_eq!(at, path);
+            }
+            _ => panic!("squashed a branch checked out in another worktree"),
+        }
+    }
+
+    #[test]
+    fn worktree_squash_inside_linked_worktree() {
+        let _env = env_lock();
+        let test = TestRepo::branched("worktree-inside");
+        test.branch("topic");
+        let path = test.add_worktree("topic", "topic");
+        let feature = test.head();
+        let opts = SquashOptions {
+            dir: path.clone(),
+            ..test.opts("master")
+        };
+
+        // The dirty check reads the index of the linked worktree
+        fs::write(path.join("f1"), "changed\n").unwrap();
+        assert!(matches!(run(&opts), Err(GitSquashError::DirtyRepo(_))));
+        fs::write(path.join("f1"), "1\n").unwrap();
+        fs::write(test.dir.join("f2"), "changed\n").unwrap();
+
+        run(&opts).unwrap();
+        let squashed = test.repo.find_commit(test.branch_tip("topic")).unwrap();
+        assert_eq!(squashed.parent_count(), 1);
+        assert_eq!(squashed.parent_id(0).unwrap(), test.branch_tip("master"));
+        assert_eq!(
+            squashed.tree_id(),
+            test.repo.find_commit(feature).unwrap().tree_id()
+        );
+
+        // The main worktree keeps its branch checked out
+        assert_eq!(test.head(), feature);
+        assert_eq!(
+            test.repo.find_reference("HEAD").unwrap().symbolic_target(),
+            Some("refs/heads/feature")
+        );
+    }
+
+    #[test]
+    fn encoding_header_is_kept() {
+        let _env = env_lock();
+        let test = TestRepo::branched("encoding");
+        let parent = test.repo.find_commit(test.head()).unwrap();
+        let sig = TestRepo::signature(6000);
+        let latin1 = create_commit(
+            &test.repo,
+            &SquashOptions::default(),
+            &sig,
+            &sig,
+            b"Caf\xe9 au lait\n",
+            Some("ISO-8859-1"),
+            &parent.tree().unwrap(),
+            &[&parent],
+            None,
+        )
+        .unwrap();
+        update_head(&test.repo, latin1, "test").unwrap();
+
+        let opts = SquashOptions {
+            message_from: MessageSource::Newest,
+            ..test.opts("master")
+        };
+        squash(&test.repo, &opts).unwrap();
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        assert_eq!(squashed.message_encoding(), Some("ISO-8859-1"));
+        assert_eq!(squashed.message_bytes(), b"Caf\xe9 au lait\n");
+        assert_eq!(decode_message(&opts, &squashed), "Caf\u{e9} au lait\n");
+    }
+
+    #[test]
+    fn new_message_in_commit_encoding() {
+        let _env = env_lock();
+        let test = TestRepo::branched("commit-encoding");
+        test.repo
+            .config()
+            .unwrap()
+            .set_str("i18n.commitEncoding", "ISO-8859-1")
+            .unwrap();
+        let file = test.repo.path().join("squash.msg");
+
+        fs::write(&file, "Caf\u{e9} au lait\n").unwrap();
+        let opts = SquashOptions {
+            message_file: Some(file.clone()),
+            ..test.opts("master")
+        };
+        squash(&test.repo, &opts).unwrap();
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        assert_eq!(squashed.message_encoding(), Some("ISO-8859-1"));
+        assert_eq!(squashed.message_bytes(), b"Caf\xe9 au lait\n");
+
+        // What the encoding cannot hold is written as UTF-8
+        test.commit("f4", "4\n", "feature 4", 6000);
+        fs::write(&file, "\u{65e5}\u{672c}\n").unwrap();
+        squash(&test.repo, &opts).unwrap();
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        assert_eq!(squashed.message_encoding(), None);
+        assert_eq!(squashed.message(), Some("\u{65e5}\u{672c}\n"));
+    }
+
+    #[test]
+    fn onto_base_tip() {
+        let _env = env_lock();
+        let test = TestRepo::branched("onto-base");
+        let feature = test.head();
+        test.checkout("master");
+        let master = test.commit("m", "m\n", "base 3", 6000);
+        test.checkout("feature");
+
+        let opts = SquashOptions {
+            onto_base: true,
+            ..test.opts("master")
+        };
+        let outcome = squash(&test.repo, &opts).unwrap();
+        assert_eq!(outcome.head, feature);
+        assert_eq!(outcome.onto, master);
+
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        assert_eq!(squashed.parent_ids().collect::<Vec<_>>(), vec![master]);
+        let tree = squashed.tree().unwrap();
+        for path in &["base", "m", "f1", "f2", "f3"] {
+            assert!(tree.get_name(path).is_some(), "{} is missing", path);
+        }
+        assert!(test.dir.join("m").exists());
+    }
+
+    #[test]
+    fn onto_base_refuses_part_of_the_branch() {
+        let _env = env_lock();
+        let test = TestRepo::branched("onto-partial");
+        let feature = test.head();
+        test.checkout("master");
+        test.commit("m", "m\n", "base 3", 6000);
+        test.checkout("feature");
+
+        let partial = [
+            SquashOptions {
+                count: Some(2),
+                ..test.opts("master")
+            },
+            SquashOptions {
+                split_at: Some("HEAD~2".to_string()),
+                ..test.opts("master")
+            },
+            SquashOptions {
+                into: Some("HEAD~1".to_string()),
+                ..test.opts("master")
+            },
+            SquashOptions {
+                since: Some("@4500".to_string()),
+                ..test.opts("master")
+            },
+        ];
+        for opts in partial {
+            let opts = SquashOptions {
+                onto_base: true,
+                ..opts
+            };
+            assert!(matches!(
+                squash(&test.repo, &opts),
+                Err(GitSquashError::PartialOnto(ref base)) if base == "master"
+            ));
+            assert_eq!(test.head(), feature);
+        }
+
+        // --split-at the merge base is still the whole branch
+        let opts = SquashOptions {
+            onto_base: true,
+            split_at: Some("master~1".to_string()),
+            ..test.opts("master")
+        };
+        squash(&test.repo, &opts).unwrap();
+        let tree = test.repo.find_commit(test.head()).unwrap().tree().unwrap();
+        for path in &["m", "f1", "f2", "f3"] {
+            assert!(tree.get_name(path).is_some(), "{} is missing", path);
+        }
+    }
+
+    #[test]
+    fn onto_base_conflicts() {
+        let _env = env_lock();
+        let test = TestRepo::branched("onto-conflicts");
+        let feature = test.head();
+        test.checkout("master");
+        test.commit("f2", "other\n", "base 3", 6000);
+        test.checkout("feature");
+
+        let opts = SquashOptions {
+            onto_base: true,
+            ..test.opts("master")
+        };
+        match squash(&test.repo, &opts) {
+            Err(GitSquashError::Conflicts(base, paths)) => {
+                assert_eq!(base, "master");
+                assert_eq!(paths, vec!["f2".to_string()]);
+            }
+            _ => panic!("applied a conflicting change onto the base"),
+        }
+        assert_eq!(test.head(), feature);
+    }
+
+    #[test]
+    fn root_commit_against_itself() {
+        let _env = env_lock();
+        let test = TestRepo::new("root-commit");
+        let root = test.commit("base", "1\n", "base 1", 1000);
+        let opts = SquashOptions {
+            no_protect: true,
+            ..test.opts("master")
+        };
+
+        let outcome = squash(&test.repo, &opts).unwrap();
+        assert!(matches!(outcome.kind, OutcomeKind::Contained(ref base, 0) if base == "master"));
+        assert!(outcome.commits.is_empty());
+        assert_eq!(test.head(), root);
+
+        let opts = SquashOptions {
+            require_squash: true,
+            ..opts
+        };
+        assert!(matches!(
+            squash(&test.repo, &opts),
+            Err(GitSquashError::NothingToSquash(0))
+        ));
+    }
+
+    #[test]
+    fn unborn_branch_has_no_commits() {
+        let _env = env_lock();
+        let test = TestRepo::new("unborn");
+        let outcome = squash(&test.repo, &test.opts("master")).unwrap();
+        assert!(matches!(outcome.kind, OutcomeKind::NoCommits));
+        assert_eq!(outcome.branch, "master");
+    }
+
+    #[test]
+    fn single_commit_is_left_alone() {
+        let _env = env_lock();
+        let test = TestRepo::new("single-commit");
+        test.commit("base", "1\n", "base 1", 1000);
+        test.branch("feature");
+        test.checkout("feature");
+        let tip = test.commit("f1", "1\n", "feature 1", 2000);
+
+        let outcome = squash(&test.repo, &test.opts("master")).unwrap();
+        assert!(matches!(outcome.kind, OutcomeKind::SingleCommit));
+        assert_eq!(test.head(), tip);
+    }
+
+    #[test]
+    fn committer_from_environment() {
+        let _env = env_lock();
+        let test = TestRepo::branched("committer-env");
+        let vars = [
+            ("GIT_COMMITTER_NAME", "C O Mitter"),
+            ("GIT_COMMITTER_EMAIL", "committer@example.com"),
+            ("GIT_COMMITTER_DATE", "1234567890 +0200"),
+            ("GIT_AUTHOR_NAME", "A U Thor"),
+        ];
+        for &(var, value) in &vars {
+            env::set_var(var, value);
+        }
+        let result = squash(&test.repo, &test.opts("master"));
+        let author = identity(&test.repo, "AUTHOR");
+        for &(var, _) in &vars {
+            env::remove_var(var);
+        }
+        result.unwrap();
+
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        let committer = squashed.committer();
+        assert_eq!(committer.name(), Some("C O Mitter"));
+        assert_eq!(committer.email(), Some("committer@example.com"));
+        assert_eq!(committer.when().seconds(), 1_234_567_890);
+        assert_eq!(committer.when().offset_minutes(), 120);
+
+        // Only the name is overridden, the email comes from the config
+        let author = author.unwrap();
+        assert_eq!(author.name(), Some("A U Thor"));
+        assert_eq!(author.email(), Some("tester@example.com"));
+    }
+
+    #[test]
+    fn ignored_and_unchanged_files_are_not_dirty() {
+        let test = TestRepo::branched("dirty-check");
+        let strict = SquashOptions {
+            strict: true,
+            ..test.opts("master")
+        };
+        let dirty = |opts: &SquashOptions| {
+            let statuses = worktree_status(&test.repo, opts).unwrap();
+            is_dirty(&statuses, opts.strict)
+        };
+
+        fs::write(
+            test.repo.path().join("info").join("exclude"),
+            "*.log\nbuild/\n",
+        )
+        .unwrap();
+        fs::write(test.dir.join("debug.log"), "log\n").unwrap();
+        fs::create_dir_all(test.dir.join("build")).unwrap();
+        fs::write(test.dir.join("build").join("out"), "out\n").unwrap();
+        // Rewritten with the same content, only its stat data changes
+        fs::write(test.dir.join("f1"), "1\n").unwrap();
+        assert!(!dirty(&strict));
+
+        // Untracked files only count with --strict
+        fs::write(test.dir.join("notes"), "notes\n").unwrap();
+        assert!(!dirty(&test.opts("master")));
+        assert!(dirty(&strict));
+        fs::remove_file(test.dir.join("notes")).unwrap();
+
+        fs::write(test.dir.join("f1"), "changed\n").unwrap();
+        let statuses = worktree_status(&test.repo, &strict).unwrap();
+        assert_eq!(
+            dirt(&statuses, true),
+            vec![("f1".to_string(), FileState::Modified)]
+        );
+    }
+
+    #[test]
+    fn config_from_included_files() {
+        let test = TestRepo::branched("config-include");
+        let included = test.repo.path().join("squash.inc");
+        fs::write(&included, "[squash]\n\tprotectedBranches = release/*\n").unwrap();
+        let conditional = test.repo.path().join("squash-here.inc");
+        fs::write(&conditional, "[squash]\n\tbase = master\n").unwrap();
+
+        let mut config = test.repo.config().unwrap();
+        config
+            .set_str("include.path", &included.to_string_lossy())
+            .unwrap();
+        config
+            .set_str(
+                &format!("includeIf.gitdir:{}/.path", test.dir.display()),
+                &conditional.to_string_lossy(),
+            )
+            .unwrap();
+        let repo = git2::Repository::open(&test.dir).unwrap();
+
+        let base = config_base(&repo, &SquashOptions::default(), &repo.head().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(base.name, "master");
+        assert_eq!(base.oid, test.branch_tip("master"));
+        assert!(matches!(base.source, BaseSource::Config(ref key) if key == "squash.base"));
+
+        let opts = SquashOptions::default();
+        assert_eq!(
+            protected_by(&repo, &opts, "release/1.0").unwrap(),
+            Some("release/*".to_string())
+        );
+        assert_eq!(protected_by(&repo, &opts, "master").unwrap(), None);
+    }
+
+    #[test]
+    fn oldest_commit_with_manufactured_timestamps() {
+        let _env = env_lock();
+        let test = TestRepo::new("timestamps");
+        let base = test.commit("base", "1\n", "base 1", 5000);
+        test.branch("feature");
+        test.checkout("feature");
+        // Newer commits dated before older ones, and all before the base
+        let first = test.commit("f1", "1\n", "feature 1", 3000);
+        let second = test.commit("f2", "2\n", "feature 2", 1000);
+        let third = test.commit("f3", "3\n", "feature 3", 2000);
+
+        let opts = SquashOptions {
+            dry_run: true,
+            ..test.opts("master")
+        };
+        let outcome = squash(&test.repo, &opts).unwrap();
+        let ids: Vec<git2::Oid> = outcome.commits.iter().map(|c| c.id).collect();
+        assert_eq!(ids, vec![third, second, first]);
+        assert_eq!(
+            oldest_commit(&test.repo, &[second, third, first], third, base).unwrap(),
+            first
+        );
+
+        squash(&test.repo, &test.opts("master")).unwrap();
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        assert_eq!(squashed.message(), Some("feature 1"));
+        assert_eq!(squashed.parent_id(0).unwrap(), base);
+    }
+
+    #[test]
+    fn keep_author_keeps_the_offset() {
+        let _env = env_lock();
+        let test = TestRepo::new("keep-author");
+        test.commit("base", "1\n", "base 1", 1000);
+        test.branch("feature");
+        test.checkout("feature");
+        let author = git2::Signature::new(
+            "A U Thor",
+            "author@example.com",
+            &git2::Time::new(1_500_000_000, -330),
+        )
+        .unwrap();
+        let committer = TestRepo::signature(2000);
+        test.commit_as("f1", "1\n", "feature 1", &author, &committer);
+        test.commit_as("f2", "2\n", "feature 2", &author, &committer);
+
+        let opts = SquashOptions {
+            keep_author: true,
+            ..test.opts("master")
+        };
+        squash(&test.repo, &opts).unwrap();
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        let kept = squashed.author();
+        assert_eq!(kept.name(), Some("A U Thor"));
+        assert_eq!(kept.email(), Some("author@example.com"));
+        assert_eq!(kept.when().seconds(), 1_500_000_000);
+        assert_eq!(kept.when().offset_minutes(), -330);
+    }
+
+    #[test]
+    fn message_from_picks_a_squashed_commit() {
+        let _env = env_lock();
+        let sources = vec![
+            (MessageSource::Oldest, "feature 1"),
+            (MessageSource::Newest, "feature 3"),
+            (MessageSource::Rev("HEAD~1".to_string()), "feature 2"),
+        ];
+        for (i, (source, message)) in sources.into_iter().enumerate() {
+            let test = TestRepo::branched(&format!("message-from-{}", i));
+            let opts = SquashOptions {
+                message_from: source,
+                ..test.opts("master")
+            };
+            squash(&test.repo, &opts).unwrap();
+            let squashed = test.repo.find_commit(test.head()).unwrap();
+            assert_eq!(squashed.message(), Some(message));
+        }
+
+        // Only a commit being squashed can give the message
+        let test = TestRepo::branched("message-from-base");
+        let head = test.head();
+        let opts = SquashOptions {
+            message_from: MessageSource::Rev("master".to_string()),
+            ..test.opts("master")
+        };
+        assert!(matches!(
+            squash(&test.repo, &opts),
+            Err(GitSquashError::MessageNotInRange(ref rev)) if rev == "master"
+        ));
+        assert_eq!(test.head(), head);
+    }
+
+    #[test]
+    fn reuse_message_from_any_commit() {
+        let _env = env_lock();
+        let test = TestRepo::branched("reuse-message");
+        let opts = SquashOptions {
+            reuse_message: Some("master".to_string()),
+            ..test.opts("master")
+        };
+        squash(&test.repo, &opts).unwrap();
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        assert_eq!(squashed.message(), Some("base 2"));
+        assert_eq!(squashed.parent_id(0).unwrap(), test.branch_tip("master"));
+
+        // A single commit is rewritten to take the message
+        let opts = SquashOptions {
+            reuse_message: Some("master~1".to_string()),
+            ..test.opts("master")
+        };
+        let outcome = squash(&test.repo, &opts).unwrap();
+        assert!(outcome.tip.is_some());
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        assert_eq!(squashed.message(), Some("base 1"));
+        assert_eq!(squashed.parent_id(0).unwrap(), outcome.onto);
+    }
+
+    #[test]
+    fn run_returns_the_outcome() {
+        let _env = env_lock();
+        let test = TestRepo::branched("outcome");
+        let head = test.head();
+        let master = test.branch_tip("master");
+
+        let outcome = run(&test.opts("master")).unwrap();
+        assert!(matches!(outcome.kind, OutcomeKind::Squashed));
+        assert_eq!(outcome.branch, "feature");
+        assert_eq!(outcome.head, head);
+        assert_eq!(outcome.merge_base, master);
+        assert_eq!(outcome.onto, master);
+        assert_eq!(outcome.tip, Some(test.head()));
+        assert_ne!(test.head(), head);
+        let summaries: Vec<&str> = outcome.commits.iter().map(|c| c.summary.as_str()).collect();
+        assert_eq!(summaries, vec!["feature 3", "feature 2", "feature 1"]);
+        assert_eq!(outcome.commits[0].id, head);
+        assert!(!outcome.wip);
+
+        let backup = outcome.backup.unwrap();
+        assert_eq!(test.repo.refname_to_id(&backup).unwrap(), head);
+    }
+
+    // A stand-in for gpg that keeps what it was asked to sign and answers
+    // with a fixed signature and the status line gpg prints
+    #[cfg(unix)]
+    #[test]
+    fn resign_signs_when_a_commit_was_signed() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _env = env_lock();
+        let test = TestRepo::branched("resign");
+        let signed_data = test.repo.path().join("signed-data");
+        let program = test.repo.path().join("fake-gpg");
+        fs::write(
+            &program,
+            format!(
+                "#!/bin/sh\ncat > '{}'\n\
+                 printf -- '-----BEGIN PGP SIGNATURE-----\\n\\nsquashed\\n-----END PGP SIGNATURE-----\\n'\n\
+                 echo '[GNUPG:] SIG_CREATED D 1 8 00 1234567890 ABCDEF' >&2\n",
+                signed_data.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let opts = SquashOptions {
+            resign: true,
+            gpg_program: Some(program.to_string_lossy().into_owned()),
+            ..test.opts("master")
+        };
+
+        // None of the commits are signed, so neither is the squash
+        squash(&test.repo, &opts).unwrap();
+        assert!(test.repo.extract_signature(&test.head(), None).is_err());
+        assert!(!signed_data.exists());
+
+        let head = test.repo.find_commit(test.head()).unwrap();
+        let sig = TestRepo::signature(6000);
+        let buffer = test
+            .repo
+            .commit_create_buffer(&sig, &sig, "feature 4\n", &head.tree().unwrap(), &[&head])
+            .unwrap();
+        let signed = test
+            .repo
+            .commit_signed(
+                buffer.as_str().unwrap(),
+                "-----BEGIN PGP SIGNATURE-----\n\nfeature\n-----END PGP SIGNATURE-----\n",
+                None,
+            )
+            .unwrap();
+        update_head(&test.repo, signed, "test").unwrap();
+        assert_eq!(count_signed(&test.repo, &[signed]).unwrap(), 1);
+
+        squash(&test.repo, &opts).unwrap();
+        let (signature, data) = test.repo.extract_signature(&test.head(), None).unwrap();
+        assert_eq!(
+            signature.as_str().unwrap(),
+            "-----BEGIN PGP SIGNATURE-----\n\nsquashed\n-----END PGP SIGNATURE-----\n"
+        );
+        assert_eq!(fs::read(&signed_data).unwrap(), &*data);
+    }
+
+    #[test]
+    fn preview_changes_nothing() {
+        let _env = env_lock();
+        let test = TestRepo::branched("preview");
+        let head = test.head();
+        fs::write(
+            test.repo.path().join("squash.msg"),
+            "Squashed  \n\n\nfeature 1 to 3\n\n",
+        )
+        .unwrap();
+
+        let opts = SquashOptions {
+            preview: true,
+            message_file: Some(test.repo.path().join("squash.msg")),
+            collect_trailers: true,
+            ..test.opts("master")
+        };
+        let outcome = squash(&test.repo, &opts).unwrap();
+        match outcome.kind {
+            OutcomeKind::Preview(ref message) => {
+                assert_eq!(message, b"Squashed\n\nfeature 1 to 3\n")
+            }
+            _ => panic!("no message to preview"),
+        }
+        assert_eq!(test.head(), head);
+        assert!(outcome.tip.is_none());
+        assert!(outcome.backup.is_none());
+        assert!(test
+            .repo
+            .references_glob("refs/squash-backup/*")
+            .unwrap()
+            .next()
+            .is_none());
+    }
+
+    #[test]
+    fn detected_base_is_not_in_the_preview_output() {
+        let _env = env_lock();
+        let test = TestRepo::branched("preview-base");
+
+        for &(preview, print_base) in &[(true, false), (false, true)] {
+            let recorder = Recorder::default();
+            let opts = SquashOptions {
+                base: None,
+                quiet: false,
+                preview,
+                print_base,
+                reporter: Some(Box::new(recorder.clone())),
+                ..test.opts("master")
+            };
+            squash(&test.repo, &opts).unwrap();
+            let messages = recorder.messages();
+            assert!(messages.iter().all(|&(level, _)| level != Level::Info));
+            assert!(messages.contains(&(
+                Level::Status,
+                "Using master as the base (detected default branch)".to_string()
+            )));
+        }
+    }
+
+    #[test]
+    fn committer_date_is_author_date() {
+        let _env = env_lock();
+        let test = TestRepo::new("committer-date");
+        test.commit("base", "1\n", "base 1", 1000);
+        test.branch("feature");
+        test.checkout("feature");
+        let author = git2::Signature::new(
+            "Tester",
+            "tester@example.com",
+            &git2::Time::new(1_600_000_000, 60),
+        )
+        .unwrap();
+        let committer = TestRepo::signature(1_700_000_000);
+        test.commit_as("f1", "1\n", "feature 1", &author, &committer);
+        test.commit_as("f2", "2\n", "feature 2", &author, &committer);
+
+        let opts = SquashOptions {
+            keep_author: true,
+            committer_date_is_author_date: true,
+            ..test.opts("master")
+        };
+        squash(&test.repo, &opts).unwrap();
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        for sig in &[squashed.author(), squashed.committer()] {
+            assert_eq!(sig.when().seconds(), 1_600_000_000);
+            assert_eq!(sig.when().offset_minutes(), 60);
+        }
+    }
+
+    #[test]
+    fn commit_graph_single_file_or_chain() {
+        let test = TestRepo::branched("commit-graph");
+        let info = test.repo.path().join("objects").join("info");
+        assert_eq!(commit_graph(&test.repo), CommitGraph::Missing);
+
+        fs::create_dir_all(info.join("commit-graphs")).unwrap();
+        assert_eq!(commit_graph(&test.repo), CommitGraph::Chain);
+
+        // A single file is read even when a chain is next to it, unless it
+        // has generation data
+        let path = info.join("commit-graph");
+        fs::write(
+            &path,
+            b"CGPH\x01\x01\x01\x00GDA2\x00\x00\x00\x00\x00\x00\x00\x14",
+        )
+        .unwrap();
+        let unsupported = CommitGraph::Unsupported(canonical(&path));
+        assert_eq!(commit_graph(&test.repo), unsupported);
+        fs::write(
+            &path,
+            b"CGPH\x01\x01\x01\x00OIDF\x00\x00\x00\x00\x00\x00\x00\x14",
+        )
+        .unwrap();
+        let file = CommitGraph::File(canonical(&path));
+        assert_eq!(commit_graph(&test.repo), file);
+
+        // Linked worktrees share the graph of the common directory
+        let path = test.add_worktree("second", "master");
+        assert_eq!(commit_graph(&git2::Repository::open(&path).unwrap()), file);
+    }
+
+    #[test]
+    fn walk_reads_the_commit_graph() {
+        let test = TestRepo::branched("commit-graph-walk");
+        let written = process::Command::new("git")
+            .args([
+                "-c",
+                "commitGraph.generationVersion=1",
+                "commit-graph",
+                "write",
+                "--reachable",
+            ])
+            .current_dir(&test.dir)
+            .env("GIT_DIR", test.repo.path())
+            .status();
+        if !written.map(|s| s.success()).unwrap_or(false) {
+            // No git to write the graph with
+            return;
+        }
+        assert!(matches!(commit_graph(&test.repo), CommitGraph::File(_)));
+
+        // Without the object of a commit in the middle, the walk only gets
+        // past it with the parents from the graph
+        let head = test.head();
+        let middle = test.repo.find_commit(head).unwrap().parent_id(0).unwrap();
+        let hex = middle.to_string();
+        fs::remove_file(
+            test.repo
+                .path()
+                .join("objects")
+                .join(&hex[..2])
+                .join(&hex[2..]),
+        )
+        .unwrap();
+        let repo = git2::Repository::open(&test.dir).unwrap();
+        assert!(repo.find_commit(middle).is_err());
+
+        let master = test.branch_tip("master");
+        assert_eq!(repo.merge_base(master, head).unwrap(), master);
+        let mut revwalk = repo.revwalk().unwrap();
+        revwalk.push(head).unwrap();
+        revwalk.hide(master).unwrap();
+        assert_eq!(revwalk.count(), 3);
+    }
+
+    #[test]
+    fn count_squashes_the_newest_commits() {
+        let _env = env_lock();
+        let test = TestRepo::branched("count");
+        let first = test.repo.revparse_single("HEAD~2").unwrap().id();
+
+        let opts = SquashOptions {
+            count: Some(2),
+            ..test.opts("master")
+        };
+        let outcome = squash(&test.repo, &opts).unwrap();
+        let summaries: Vec<&str> = outcome.commits.iter().map(|c| c.summary.as_str()).collect();
+        assert_eq!(summaries, vec!["feature 3", "feature 2"]);
+        assert_eq!(outcome.onto, first);
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        assert_eq!(squashed.parent_id(0).unwrap(), first);
+        assert_eq!(squashed.message(), Some("feature 2"));
+
+        let opts = SquashOptions {
+            count: Some(5),
+            ..test.opts("master")
+        };
+        assert!(matches!(
+            squash(&test.repo, &opts),
+            Err(GitSquashError::CountTooLarge(5, 2))
+        ));
+    }
+
+    #[test]
+    fn into_folds_the_later_commits() {
+        let _env = env_lock();
+        let test = TestRepo::branched("into");
+        let first = test.repo.revparse_single("HEAD~2").unwrap().id();
+
+        let opts = SquashOptions {
+            into: Some("master".to_string()),
+            ..test.opts("master")
+        };
+        assert!(matches!(
+            squash(&test.repo, &opts),
+            Err(GitSquashError::NotOnBranch(ref rev, ref branch))
+                if rev == "master" && branch == "feature"
+        ));
+
+        let opts = SquashOptions {
+            into: Some("HEAD~1".to_string()),
+            ..test.opts("master")
+        };
+        let outcome = squash(&test.repo, &opts).unwrap();
+        assert_eq!(outcome.commits.len(), 2);
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        assert_eq!(squashed.parent_id(0).unwrap(), first);
+        assert_eq!(squashed.message(), Some("feature 2"));
+        assert!(squashed.tree().unwrap().get_name("f3").is_some());
+    }
+
+    #[test]
+    fn split_at_keeps_the_commits_up_to_it() {
+        let _env = env_lock();
+        let test = TestRepo::branched("split-at");
+        let first = test.repo.revparse_single("HEAD~2").unwrap().id();
+
+        let opts = SquashOptions {
+            split_at: Some("master~1".to_string()),
+            ..test.opts("master")
+        };
+        assert!(matches!(
+            squash(&test.repo, &opts),
+            Err(GitSquashError::NotOnBranch(ref rev, _)) if rev == "master~1"
+        ));
+
+        let opts = SquashOptions {
+            split_at: Some("HEAD~2".to_string()),
+            ..test.opts("master")
+        };
+        let outcome = squash(&test.repo, &opts).unwrap();
+        let summaries: Vec<&str> = outcome.commits.iter().map(|c| c.summary.as_str()).collect();
+        assert_eq!(summaries, vec!["feature 3", "feature 2"]);
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        assert_eq!(squashed.parent_id(0).unwrap(), first);
+        assert_eq!(squashed.message(), Some("feature 2"));
+    }
+
+    #[test]
+    fn since_squashes_the_newer_commits() {
+        let _env = env_lock();
+        let test = TestRepo::branched("since");
+        let first = test.repo.revparse_single("HEAD~2").unwrap().id();
+
+        let opts = SquashOptions {
+            since: Some("@3500".to_string()),
+            ..test.opts("master")
+        };
+        let outcome = squash(&test.repo, &opts).unwrap();
+        let summaries: Vec<&str> = outcome.commits.iter().map(|c| c.summary.as_str()).collect();
+        assert_eq!(summaries, vec!["feature 3", "feature 2"]);
+        assert_eq!(outcome.onto, first);
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        assert_eq!(squashed.parent_id(0).unwrap(), first);
+        assert_eq!(squashed.message(), Some("feature 2"));
+
+        // Nothing is newer than the tip
+        let test = TestRepo::branched("since-none");
+        let head = test.head();
+        let opts = SquashOptions {
+            since: Some("@9000".to_string()),
+            ..test.opts("master")
+        };
+        let outcome = squash(&test.repo, &opts).unwrap();
+        assert!(outcome.commits.is_empty());
+        assert!(outcome.tip.is_none());
+        assert_eq!(test.head(), head);
+    }
+
+    #[test]
+    fn path_keeps_other_changes_out() {
+        let _env = env_lock();
+        let test = TestRepo::new("path");
+        test.commit("base", "1\n", "base 1", 1000);
+        test.branch("feature");
+        test.checkout("feature");
+        fs::create_dir_all(test.dir.join("lib")).unwrap();
+        fs::create_dir_all(test.dir.join("doc")).unwrap();
+        test.commit("lib/a", "a\n", "feature 1", 2000);
+        test.commit("doc/b", "b\n", "feature 2", 3000);
+        test.commit("lib/c", "c\n", "feature 3", 4000);
+        let head = test.head();
+
+        let opts = SquashOptions {
+            path: Some("nope".to_string()),
+            ..test.opts("master")
+        };
+        assert!(matches!(
+            squash(&test.repo, &opts),
+            Err(GitSquashError::NoSuchPath(ref path)) if path == "nope"
+        ));
+        assert_eq!(test.head(), head);
+
+        let opts = SquashOptions {
+            path: Some("lib".to_string()),
+            ..test.opts("master")
+        };
+        squash(&test.repo, &opts).unwrap();
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        assert_eq!(squashed.parent_id(0).unwrap(), test.branch_tip("master"));
+        let tree = squashed.tree().unwrap();
+        for path in &["base", "lib/a", "lib/c"] {
+            assert!(
+                tree.get_path(Path::new(path)).is_ok(),
+                "{} is missing",
+                path
+            );
+        }
+        assert!(tree.get_name("doc").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commit_msg_hook_runs_only_for_the_squash() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _env = env_lock();
+        let test = TestRepo::branched("commit-msg");
+        let ran = test.repo.path().join("hook-ran");
+        let hooks = test.repo.path().join("hooks");
+        fs::create_dir_all(&hooks).unwrap();
+        let hook = hooks.join("commit-msg");
+        fs::write(
+            &hook,
+            format!(
+                "#!/bin/sh\ntouch '{}'\necho 'Checked-by: hook' >> \"$1\"\n",
+                ran.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let opts = SquashOptions {
+            preview: true,
+            ..test.opts("master")
+        };
+        let outcome = squash(&test.repo, &opts).unwrap();
+        assert!(matches!(outcome.kind, OutcomeKind::Preview(ref m) if m == b"feature 1"));
+        assert!(!ran.exists());
+
+        squash(&test.repo, &test.opts("master")).unwrap();
+        assert!(ran.exists());
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        assert!(squashed.message().unwrap().ends_with("Checked-by: hook\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn post_squash_sees_the_squash_and_rolls_back() {
+        let _env = env_lock();
+        let test = TestRepo::branched("post-squash");
+        let old = test.head();
+        let env = test.repo.path().join("post-squash-env");
+
+        let opts = SquashOptions {
+            post_squash: Some(format!(
+                "echo \"$GIT_SQUASH_OLD_OID $GIT_SQUASH_NEW_OID $GIT_SQUASH_BASE $GIT_SQUASH_BRANCH\" > '{}'",
+                env.display()
+            )),
+            ..test.opts("master")
+        };
+        squash(&test.repo, &opts).unwrap();
+        assert_eq!(
+            fs::read_to_string(&env).unwrap(),
+            format!(
+                "{} {} {} feature\n",
+                old,
+                test.head(),
+                test.branch_tip("master")
+            )
+        );
+
+        // A failing command puts the branch back with --post-squash-rollback
+        test.commit("f4", "4\n", "feature 4", 6000);
+        let old = test.head();
+        let opts = SquashOptions {
+            post_squash: Some("false".to_string()),
+            post_squash_rollback: true,
+            ..test.opts("master")
+        };
+        assert!(matches!(
+            squash(&test.repo, &opts),
+            Err(GitSquashError::PostSquash(ref command, true)) if command == "false"
+        ));
+        assert_eq!(test.head(), old);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn foreign_authors_are_refused_before_the_hook_runs() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _env = env_lock();
+        let test = TestRepo::branched("foreign-hook");
+        let other =
+            git2::Signature::new("Other", "other@example.com", &git2::Time::new(6000, 0)).unwrap();
+        test.commit_as("f4", "4\n", "feature 4", &other, &other);
+        let ran = test.repo.path().join("hook-ran");
+        let hooks = test.repo.path().join("hooks");
+        fs::create_dir_all(&hooks).unwrap();
+        let hook = hooks.join("commit-msg");
+        fs::write(&hook, format!("#!/bin/sh\ntouch '{}'\n", ran.display())).unwrap();
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let head = test.head();
+        assert!(matches!(
+            squash(&test.repo, &test.opts("master")),
+            Err(GitSquashError::ForeignAuthors(_))
+        ));
+        assert!(!ran.exists());
+        assert_eq!(test.head(), head);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn interactive_rebuilds_the_branch_from_the_todo() {
+        let _env = env_lock();
+        // keep f1, squash f2 into it, then keep f3 or drop f2
+        for (name, edit) in &[
+            ("interactive", "3s/^squash/keep/"),
+            ("interactive-drop", "2s/^squash/drop/;3s/^squash/keep/"),
+        ] {
+            let test = TestRepo::branched(name);
+            let tip = test.repo.find_commit(test.head()).unwrap();
+            test.repo
+                .config()
+                .unwrap()
+                .set_str("sequence.editor", &format!("sed -i.bak '{}'", edit))
+                .unwrap();
+
+            let opts = SquashOptions {
+                interactive: true,
+                ..test.opts("master")
+            };
+            squash(&test.repo, &opts).unwrap();
+            let head = test.repo.find_commit(test.head()).unwrap();
+            assert_eq!(head.message(), Some("feature 3"));
+            let first = head.parent(0).unwrap();
+            assert_eq!(first.parent_id(0).unwrap(), test.branch_tip("master"));
+            let tree = first.tree().unwrap();
+            assert!(tree.get_name("f1").is_some());
+            assert!(tree.get_name("f3").is_none());
+            if *name == "interactive" {
+                assert!(tree.get_name("f2").is_some());
+                assert_eq!(head.tree_id(), tip.tree_id());
+            } else {
+                assert!(tree.get_name("f2").is_none());
+                assert!(head.tree().unwrap().get_name("f2").is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn autostash_puts_the_changes_back() {
+        let _env = env_lock();
+        let test = TestRepo::branched("autostash");
+        fs::write(test.dir.join("f1"), "changed\n").unwrap();
+
+        // Refused without stashing
+        assert!(matches!(
+            run(&test.opts("master")),
+            Err(GitSquashError::DirtyRepo(_))
+        ));
+
+        let opts = SquashOptions {
+            autostash: Some(true),
+            ..test.opts("master")
+        };
+        let outcome = run(&opts).unwrap();
+        assert_eq!(outcome.tip, Some(test.head()));
+        let squashed = test.repo.find_commit(test.head()).unwrap();
+        let f1 = squashed.tree().unwrap().get_name("f1").unwrap().id();
+        assert_eq!(test.repo.find_blob(f1).unwrap().content(), b"1\n");
+        assert_eq!(
+            fs::read_to_string(test.dir.join("f1")).unwrap(),
+            "changed\n"
+        );
+
+        let mut repo = git2::Repository::open(&test.dir).unwrap();
+        let mut stashes = 0;
+        repo.stash_foreach(|_, _, _| {
+            stashes += 1;
+            true
+        })
+        .unwrap();
+        assert_eq!(stashes, 0);
+    }
+
+    #[test]
+    fn wip_writes_the_index_only_after_the_squash() {
+        let _env = env_lock();
+        let test = TestRepo::branched("wip-index");
+        let feature = test.head();
+        test.checkout("master");
+        test.commit("f2", "other\n", "base 3", 6000);
+        test.checkout("feature");
+        fs::write(test.dir.join("f1"), "changed\n").unwrap();
+        let index_state = || {
+            let repo = git2::Repository::open(&test.dir).unwrap();
+            let statuses = worktree_status(&repo, &test.opts("master")).unwrap();
+            dirt(&statuses, false)
+        };
+
+        // The conflict comes after the changes are staged
+        let opts = SquashOptions {
+            wip: true,
+            onto_base: true,
+            ..test.opts("master")
+        };
+        assert!(matches!(
+            squash(&test.repo, &opts),
+            Err(GitSquashError::Conflicts(_, _))
+        ));
+        assert_eq!(test.head(), feature);
+        assert_eq!(index_state(), vec![("f1".to_string(), FileState::Modified)]);
+
+        let opts = SquashOptions {
+            wip: true,
+            ..test.opts("master")
+        };
+        let outcome = squash(&test.repo, &opts).unwrap();
+        assert!(outcome.wip);
+        assert!(index_state().is_empty());
+        let tree = test.repo.find_commit(test.head()).unwrap().tree().unwrap();
+        let blob = tree.get_name("f1").unwrap().to_object(&test.repo).unwrap();
+        assert_eq!(blob.as_blob().unwrap().content(), b"changed\n");
+    }
+
+    #[test]
+    fn unknown_branch_is_named() {
+        let _env = env_lock();
+        let test = TestRepo::branched("unknown-branch");
+        let not_local = |result: Result<(), GitSquashError>| match result {
+            Err(e @ GitSquashError::NotALocalBranch(_)) => e.to_string(),
+            _ => panic!("no error for a branch that does not exist"),
+        };
+
+        let opts = SquashOptions {
+            branch: Some("nope".to_string()),
+            ..test.opts("master")
+        };
+        assert_eq!(
+            not_local(squash(&test.repo, &opts).map(|_| ())),
+            "nope is not a local branch"
+        );
+        assert_eq!(
+            not_local(undo(&test.repo, Some("nope")).map(|_| ())),
+            "nope is not a local branch"
+        );
+        assert_eq!(
+            not_local(set_base(&opts, "master")),
+            "nope is not a local branch"
+        );
+    }
+
+    #[test]
+    fn advance_fast_forwards_another_branch() {
+        let _env = env_lock();
+        let test = TestRepo::branched("advance");
+        let master = test.repo.find_commit(test.branch_tip("master")).unwrap();
+        test.repo.branch("release", &master, false).unwrap();
+        test.checkout("master");
+        test.branch("diverged");
+        test.checkout("diverged");
+        test.commit("d", "d\n", "diverged", 6000);
+        test.checkout("feature");
+        let head = test.head();
+
+        // Checked before the branch is squashed
+        let opts = SquashOptions {
+            advance: Some("diverged".to_string()),
+            ..test.opts("master")
+        };
+        assert!(matches!(
+            squash(&test.repo, &opts),
+            Err(GitSquashError::NotFastForward(ref name)) if name == "diverged"
+        ));
+        assert_eq!(test.head(), head);
+
+        let opts = SquashOptions {
+            advance: Some("release".to_string()),
+            ..test.opts("master")
+        };
+        let outcome = squash(&test.repo, &opts).unwrap();
+        assert_eq!(outcome.tip, Some(test.head()));
+        assert_eq!(test.branch_tip("release"), test.head());
+    }
+
+    #[test]
+    fn push_with_a_lease() {
+        let _env = env_lock();
+        let test = TestRepo::branched("push");
+        let bare = test.repo.path().join("remote.git");
+        let remote_repo = git2::Repository::init_bare(&bare).unwrap();
+        let mut remote = test.repo.remote("origin", bare.to_str().unwrap()).unwrap();
+        remote
+            .push(
+                &[
+                    "refs/heads/master:refs/heads/master",
+                    "refs/heads/feature:refs/heads/feature",
+                ],
+                None,
+            )
+            .unwrap();
+        let mut config = test.repo.config().unwrap();
+        config.set_str("branch.feature.remote", "origin").unwrap();
+        config
+            .set_str("branch.feature.merge", "refs/heads/feature")
+            .unwrap();
+
+        let opts = SquashOptions {
+            push: true,
+            ..test.opts("master")
+        };
+        squash(&test.repo, &opts).unwrap();
+        let pushed = remote_repo.refname_to_id("refs/heads/feature").unwrap();
+        assert_eq!(pushed, test.head());
+
+        // Someone else pushed since, and it was not fetched
+        let master = test.branch_tip("master");
+        remote_repo
+            .reference("refs/heads/feature", master, true, "")
+            .unwrap();
+        test.commit("f4", "4\n", "feature 4", 6000);
+        test.commit("f5", "5\n", "feature 5", 7000);
+        match squash(&test.repo, &opts) {
+            Err(GitSquashError::Push(message)) => assert!(message.contains("not fetched")),
+            _ => panic!("pushed over a branch that moved"),
+        }
+        assert_eq!(
+            remote_repo.refname_to_id("refs/heads/feature").unwrap(),
+            master
+        );
+    }
+
+    #[test]
+    fn notes_refs_are_listed_once() {
+        let test = TestRepo::branched("notes-refs");
+        let head = test.head();
+        for name in &["refs/notes/commits", "refs/notes/review"] {
+            test.repo.reference(name, head, false, "").unwrap();
+        }
+        let mut config = test.repo.config().unwrap();
+        config
+            .set_multivar("notes.rewriteRef", "^$", "refs/notes/commits")
+            .unwrap();
+        config
+            .set_multivar("notes.rewriteRef", "^$", "refs/notes/*")
+            .unwrap();
+        config
+            .set_multivar("notes.rewriteRef", "^$", "refs/notes/commits")
+            .unwrap();
+
+        let mut refs = notes_refs(&test.repo, &SquashOptions::default()).unwrap();
+        refs.sort();
+        assert_eq!(refs, vec!["refs/notes/commits", "refs/notes/review"]);
+    }
+
+    #[test]
+    fn notes_are_copied_to_the_squashed_commit() {
+        let _env = env_lock();
+        let test = TestRepo::branched("notes");
+        let sig = TestRepo::signature(6000);
+        let oldest = test.repo.revparse_single("HEAD~2").unwrap().id();
+        test.repo
+            .note(&sig, &sig, None, oldest, "note 1\n", false)
+            .unwrap();
+        test.repo
+            .note(&sig, &sig, None, test.head(), "note 3\n", false)
+            .unwrap();
+
+        let opts = SquashOptions {
+            copy_notes: true,
+            ..test.opts("master")
+        };
+        squash(&test.repo, &opts).unwrap();
+        let note = test.repo.find_note(None, test.head()).unwrap();
+        assert_eq!(note.message(), Some("note 1\n\nnote 3\n"));
+    }
+
+    #[test]
+    fn undo_restores_the_tip_before_the_squash() {
+        let _env = env_lock();
+        let test = TestRepo::branched("undo");
+        let head = test.head();
+        squash(&test.repo, &test.opts("master")).unwrap();
+
+        assert_eq!(
+            undo(&test.repo, None).unwrap(),
+            ("feature".to_string(), head)
+        );
+        assert_eq!(test.head(), head);
+        assert!(test.dir.join("f3").exists());
+        assert!(test
+            .repo
+            .find_reference("refs/squash-backup/feature")
+            .is_err());
+        // The undo is not a squash to undo again
+        assert!(matches!(
+            undo(&test.repo, None),
+            Err(GitSquashError::NotSquashed(ref b)) if b == "feature"
+        ));
+    }
+
+    #[test]
+    fn undo_refuses_after_a_new_commit() {
+        let _env = env_lock();
+        let test = TestRepo::branched("undo-commit");
+        squash(&test.repo, &test.opts("master")).unwrap();
+        let later = test.commit("f4", "4\n", "feature 4", 6000);
+
+        assert!(matches!(
+            undo(&test.repo, None),
+            Err(GitSquashError::NotSquashed(ref b)) if b == "feature"
+        ));
+        assert_eq!(test.head(), later);
+        assert!(test.dir.join("f4").exists());
+    }
+
+    #[test]
+    fn glob_literal() {
+        assert!(glob_match("main", "main"));
+        assert!(!glob_match("main", "mainline"));
+        assert!(!glob_match("main", "mai"));
+    }
+
+    #[test]
+    fn glob_star() {
+        assert!(glob_match("*", "anything/at/all"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("release/*", "release/1.0"));
+        assert!(glob_match("release/*", "release/1.0/hotfix"));
+        assert!(glob_match("*-stable", "3.x-stable"));
+        assert!(glob_match("r*e*e", "release"));
+    }
+
+    #[test]
+    fn glob_question_mark() {
+        assert!(glob_match("v?", "v1"));
+        assert!(!glob_match("v?", "v10"));
+        assert!(!glob_match("v?", "v"));
+    }
+
+    #[test]
+    fn glob_no_match() {
+        assert!(!glob_match("release/*", "feature/release"));
+        assert!(!glob_match("*-stable", "stable-3.x"));
+        assert!(!glob_match("", "main"));
+    }
+
+    #[test]
+    fn protected_by_patterns() {
+        let test = TestRepo::new("protect");
+        let repo = &test.repo;
+
+        let opts = SquashOptions::default();
+        assert_eq!(
+            protected_by(repo, &opts, "master").unwrap(),
+            Some("master".to_string())
+        );
+        assert_eq!(protected_by(repo, &opts, "feature").unwrap(), None);
+
+        let opts = SquashOptions {
+            protect: vec!["release/*".to_string(), "v?".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            protected_by(repo, &opts, "release/2.0").unwrap(),
+            Some("release/*".to_string())
+        );
+        assert_eq!(
+            protected_by(repo, &opts, "v2").unwrap(),
+            Some("v?".to_string())
+        );
+        assert_eq!(protected_by(repo, &opts, "master").unwrap(), None);
+
+        let opts = SquashOptions {
+            protect: vec!["*".to_string()],
+            no_protect: true,
+            ..Default::default()
+        };
+        assert_eq!(protected_by(repo, &opts, "master").unwrap(), None);
+    }
+
+    #[test]
+    fn warnings_go_to_the_reporter() {
+        let _env = env_lock();
+        let test = TestRepo::branched("reporter");
+        fs::write(test.dir.join("staged"), "staged\n").unwrap();
+        let mut index = test.repo.index().unwrap();
+        index.add_path(Path::new("staged")).unwrap();
+        index.write().unwrap();
+
+        let recorder = Recorder::default();
+        let opts = SquashOptions {
+            allow_dirty: true,
+            verbose: true,
+            quiet: false,
+            reporter: Some(Box::new(recorder.clone())),
+            ..test.opts("master")
+        };
+        squash(&test.repo, &opts).unwrap();
+        let messages = recorder.messages();
+        assert!(messages.contains(&(
+            Level::Warning,
+            "the staged changes will be part of the squashed commit".to_string()
+        )));
+        assert!(messages
+            .iter()
+            .any(|&(level, ref message)| level == Level::Status
+                && message.starts_with("Using master as the base")));
+    }
+}
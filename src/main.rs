@@ -1,172 +1,1200 @@
 extern crate clap;
+extern crate env_logger;
 extern crate git2;
+extern crate git_squash_rs;
 
-use std::error;
-use std::fmt;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::PathBuf;
 use std::process;
 
-use clap::{crate_version, App, Arg};
+use clap::{crate_version, App, Arg, Shell, SubCommand};
 
-#[derive(Debug)]
-enum GitSquashError {
-    Git2(git2::Error),
-    DirtyRepo,
-    SymbolicRef(String),
-}
+use git_squash_rs::{
+    abbrev_id, open_repo, prune_backups, run, set_base, short_id, start_dir, undo, Cleanup, Level,
+    MessageSource, OutcomeKind, Reporter, SquashOptions, SquashOutcome,
+};
 
-impl fmt::Display for GitSquashError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            GitSquashError::Git2(ref e) => e.fmt(f),
-            GitSquashError::DirtyRepo => {
-                write!(f, "The repo is dirty, please stash or commit changes")
-            }
-            GitSquashError::SymbolicRef(ref r) => {
-                write!(f, "{} is a symbolic reference cannot be used for squash", r)
-            }
+// Reports from the library go to the terminal, Info on stdout so it can be
+// piped and the rest on stderr prefixed like git does
+struct Terminal;
+
+impl Reporter for Terminal {
+    fn report(&self, level: Level, message: &str) {
+        match level {
+            Level::Info => println!("{}", message),
+            Level::Status => eprintln!("{}", message),
+            Level::Note => eprintln!("note: {}", message),
+            Level::Hint => eprintln!("hint: {}", message),
+            Level::Warning => eprintln!("warning: {}", message),
+            Level::Error => eprintln!("error: {}", message),
         }
     }
-}
 
-impl error::Error for GitSquashError {
-    fn description(&self) -> &str {
-        match *self {
-            GitSquashError::Git2(ref e) => e.description(),
-            GitSquashError::DirtyRepo => "dirty repo cannot be squashed",
-            GitSquashError::SymbolicRef(ref _s) => "symbolic ref cannot be resolved",
+    // Only asked when there is someone at a terminal to answer
+    fn confirm(&self, question: &str) -> bool {
+        if !io::stdin().is_terminal() || !io::stderr().is_terminal() {
+            return false;
         }
+
+        eprint!("{} [y/N] ", question);
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).is_ok() && matches!(answer.trim(), "y" | "Y" | "yes")
     }
 
-    fn cause(&self) -> Option<&dyn error::Error> {
-        match *self {
-            GitSquashError::Git2(ref e) => Some(e),
-            GitSquashError::DirtyRepo => None,
-            GitSquashError::SymbolicRef(ref _s) => None,
+    fn progress(&self, status: Option<&str>) {
+        if !io::stderr().is_terminal() {
+            return;
+        }
+        match status {
+            Some(status) => eprint!("\r{}\x1b[K", status),
+            None => eprint!("\r\x1b[K"),
         }
     }
 }
 
-impl From<git2::Error> for GitSquashError {
-    fn from(err: git2::Error) -> GitSquashError {
-        GitSquashError::Git2(err)
-    }
-}
+fn report(repo: &git2::Repository, opts: &SquashOptions, outcome: &SquashOutcome) {
+    let short_id = |oid| abbrev_id(repo, oid, opts.abbrev);
 
-// Check if the index or working copy have changes
-fn is_dirty(statuses: &git2::Statuses) -> bool {
-    if statuses.is_empty() {
-        return false;
+    if opts.stat_only {
+        println!(
+            "squash: {} commits, base={}, branch={}",
+            outcome.commits.len(),
+            short_id(outcome.onto),
+            outcome.branch
+        );
+        return;
     }
 
-    let mut dirty_statuses = git2::Status::empty();
-    dirty_statuses.insert(git2::Status::INDEX_NEW);
-    dirty_statuses.insert(git2::Status::INDEX_MODIFIED);
-    dirty_statuses.insert(git2::Status::INDEX_DELETED);
-    dirty_statuses.insert(git2::Status::INDEX_RENAMED);
-    dirty_statuses.insert(git2::Status::INDEX_TYPECHANGE);
-    dirty_statuses.insert(git2::Status::WT_NEW);
-    dirty_statuses.insert(git2::Status::WT_MODIFIED);
-    dirty_statuses.insert(git2::Status::WT_DELETED);
-    dirty_statuses.insert(git2::Status::WT_TYPECHANGE);
-    dirty_statuses.insert(git2::Status::WT_RENAMED);
-    dirty_statuses.insert(git2::Status::CONFLICTED);
+    match outcome.kind {
+        OutcomeKind::Base => {
+            println!("{}", outcome.onto);
+            println!(
+                "{} commits between the merge base and {}",
+                outcome.commits.len(),
+                outcome.branch
+            );
+        }
+        // The message alone, as it would be written, so it can be piped
+        OutcomeKind::Preview(ref message) => {
+            let _ = io::stdout().write_all(message);
+        }
+        _ if opts.quiet => {}
+        // Nothing was changed
+        OutcomeKind::NoCommits
+        | OutcomeKind::Contained(..)
+        | OutcomeKind::SingleCommit
+        | OutcomeKind::AlreadySquashed(_)
+            if opts.quiet_if_noop => {}
+        OutcomeKind::Folded if outcome.commits.is_empty() && opts.quiet_if_noop => {}
+        OutcomeKind::NoCommits => println!("No commits to squash"),
+        OutcomeKind::Contained(ref base, 0) => println!(
+            "{} is fully contained in {}, nothing to squash",
+            outcome.branch, base
+        ),
+        OutcomeKind::Contained(ref base, behind) => println!(
+            "{} is fully contained in {} ({} {} behind), nothing to squash",
+            outcome.branch,
+            base,
+            behind,
+            if behind == 1 { "commit" } else { "commits" }
+        ),
+        OutcomeKind::SingleCommit => println!("Only one commit to squash."),
+        OutcomeKind::DryRun => {
+            println!(
+                "Would squash {} commits on {} onto {}:",
+                outcome.commits.len(),
+                outcome.branch,
+                short_id(outcome.onto)
+            );
+            for commit in &outcome.commits {
+                println!("{} {}", short_id(commit.id), commit.summary);
+            }
+        }
+        OutcomeKind::Folded if outcome.commits.is_empty() && opts.autosquash => {
+            println!("No fixup!, squash! or amend! commits to fold")
+        }
+        OutcomeKind::Folded if outcome.commits.is_empty() => println!("No commits to fold"),
+        OutcomeKind::Folded if opts.dry_run => {
+            println!(
+                "Would {} {} commits on {}:",
+                if opts.interactive {
+                    "fold or drop"
+                } else {
+                    "fold"
+                },
+                outcome.commits.len(),
+                outcome.branch
+            );
+            for commit in &outcome.commits {
+                println!("{} {}", short_id(commit.id), commit.summary);
+            }
+        }
+        OutcomeKind::Folded => {}
+        OutcomeKind::AlreadySquashed(oid) => println!(
+            "{} is already squashed, {} has the same tree",
+            outcome.branch,
+            short_id(oid)
+        ),
+        OutcomeKind::Squashed => {}
+    }
 
-    for s in statuses.iter() {
-        if s.status().intersects(dirty_statuses) {
-            return true;
+    if outcome.wip && !opts.quiet {
+        println!("Included the changes in the working copy in the squashed commit");
+    }
+    if let Some(ref backup) = outcome.backup {
+        if !opts.quiet {
+            println!("Saved the previous tip as {}", backup);
         }
     }
 
-    false
+    // Print the new tip so scripts can pick it up
+    if let Some(tip) = outcome.tip {
+        if !opts.quiet {
+            println!("{}", tip);
+        }
+    }
 }
 
-fn squash(branch_name: &str) -> Result<(), GitSquashError> {
-    let repo = git2::Repository::discover(".")?;
-
-    // Check if the index or working copy have changes
-    let statuses = repo.statuses(None)?;
-    let is_dirt = is_dirty(&statuses);
+// Squash several branches onto the same base one after the other, then list
+// how each went. Without --keep-going the first failure stops the rest.
+// Returns the exit code, the one of the first failure.
+fn squash_branches(opts: &mut SquashOptions, branches: &[&str], keep_going: bool) -> i32 {
+    let mut results = Vec::new();
+    for name in branches {
+        opts.branch = Some(name.to_string());
+        if !opts.quiet {
+            println!("Squashing {}", name);
+        }
+        let result = run(opts).and_then(|outcome| {
+            report(&open_repo(opts)?, opts, &outcome);
+            Ok(outcome)
+        });
+        if let Err(ref e) = result {
+            eprintln!("error: {}", e);
+        }
+        let failed = result.is_err();
+        results.push(result);
+        if failed && !keep_going {
+            break;
+        }
+    }
 
-    if is_dirt {
-        return Err(GitSquashError::DirtyRepo);
+    let failures = results.iter().filter(|r| r.is_err()).count();
+    if !opts.quiet || failures > 0 {
+        let repo = open_repo(opts).ok();
+        let short_id = |oid| match repo {
+            Some(ref repo) => abbrev_id(repo, oid, opts.abbrev),
+            None => short_id(oid),
+        };
+        // A branch left as it was, like one with a single commit, is not
+        // counted as squashed
+        let unchanged = results
+            .iter()
+            .filter(|r| matches!(r, Ok(outcome) if outcome.tip.is_none()))
+            .count();
+        let mut counts = format!(
+            "Squashed {} of {} branches",
+            results.len() - failures - unchanged,
+            branches.len()
+        );
+        if unchanged > 0 {
+            counts.push_str(&format!(", {} unchanged", unchanged));
+        }
+        if failures > 0 {
+            counts.push_str(&format!(", {} failed", failures));
+        }
+        println!("\n{}:", counts);
+        for (i, name) in branches.iter().enumerate() {
+            match results.get(i) {
+                Some(Ok(outcome)) => match outcome.tip {
+                    Some(tip) => println!(
+                        "    {}: squashed {} commits into {}",
+                        name,
+                        outcome.commits.len(),
+                        short_id(tip)
+                    ),
+                    None => println!("    {}: unchanged", name),
+                },
+                // The first line is enough to tell what went wrong, the
+                // whole error was printed when it happened
+                Some(Err(e)) => println!(
+                    "    {}: failed, {}",
+                    name,
+                    e.to_string().lines().next().unwrap_or_default()
+                ),
+                None => println!("    {}: not squashed after the failure", name),
+            }
+        }
     }
 
-    let head = repo.refname_to_id("HEAD")?;
+    results
+        .iter()
+        .find_map(|r| r.as_ref().err())
+        .map_or(0, |e| e.exit_code())
+}
 
-    let branch = repo
-        .find_branch(branch_name, git2::BranchType::Local)?
-        .into_reference();
-    let branch = branch
-        .target()
-        .ok_or_else(|| GitSquashError::SymbolicRef(branch_name.to_string()))?;
+// Print the completion script clap generates. The branch argument is
+// completed from the refs of the repository at the time, which clap cannot
+// express, so bash and fish get a snippet for it on top.
+fn print_completions(app: &mut App, shell: Shell) {
+    let bin = "git-squash-rs";
+    app.gen_completions_to(bin, shell, &mut io::stdout());
 
-    let mb = repo.merge_base(branch, head)?;
+    let refs = "git for-each-ref --format='%(refname:short)' refs/heads refs/remotes refs/tags";
+    match shell {
+        Shell::Bash => print!(
+            "\n\
+             _{bin}_refs() {{\n\
+             \x20   _{bin}\n\
+             \x20   local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"\n\
+             \x20   local prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"\n\
+             \x20   if [[ ${{cur}} != -* && ( ${{COMP_CWORD}} -eq 1 || ${{prev}} != -* ) ]]; then\n\
+             \x20       COMPREPLY=( $(compgen -W \"$({refs} 2>/dev/null)\" -- \"${{cur}}\") )\n\
+             \x20   fi\n\
+             }}\n\
+             complete -F _{bin}_refs -o bashdefault -o default {bin}\n",
+            bin = bin,
+            refs = refs
+        ),
+        Shell::Fish => println!(
+            "complete -c {} -n '__fish_is_first_arg' -f -a \"({} 2>/dev/null)\"",
+            bin, refs
+        ),
+        _ => {}
+    }
+}
 
-    let mut revwalk = repo.revwalk()?;
-    revwalk.push(head)?;
-    revwalk.push(mb)?;
-    revwalk.hide(branch)?;
-    let mut sort = git2::Sort::empty();
-    sort.insert(git2::Sort::TIME);
-    revwalk.set_sorting(sort);
+// Escape text for roff
+fn roff(text: &str) -> String {
+    let text = text.replace('\\', "\\\\").replace('-', "\\-");
+    if text.starts_with('.') || text.starts_with('\'') {
+        format!("\\&{}", text)
+    } else {
+        text
+    }
+}
 
-    let commits_to_squash: Result<Vec<git2::Oid>, git2::Error> = revwalk.collect();
-    let commits_to_squash = commits_to_squash?;
+// Render git-squash.1 from the help clap prints, so it lists the same
+// arguments as the binary takes
+fn man_page(app: &App) -> String {
+    let mut help = Vec::new();
+    // Wide enough that clap never wraps the help of an argument
+    app.clone()
+        .set_term_width(10_000)
+        .write_help(&mut help)
+        .unwrap();
+    let help = String::from_utf8_lossy(&help).into_owned();
 
-    if commits_to_squash.is_empty() {
-        println!("No commits to squash");
-        return Ok(());
-    } else if commits_to_squash.len() == 1 {
-        println!("Only one commit to squash.");
-        return Ok(());
+    let mut synopsis = String::new();
+    let mut arguments = String::new();
+    let mut section = "";
+    for line in help.lines() {
+        if !line.starts_with(' ') && line.ends_with(':') {
+            section = line.trim_end_matches(':');
+            if section == "ARGS" {
+                arguments.push_str(".SH ARGUMENTS\n");
+            }
+            continue;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match section {
+            "USAGE" => synopsis.push_str(&format!("{}\n", roff(line))),
+            "FLAGS" | "OPTIONS" | "ARGS" => {
+                // The argument and its help are separated by a run of spaces
+                let (arg, text) = match line.find("  ") {
+                    Some(i) => (&line[..i], line[i..].trim()),
+                    None => (line, ""),
+                };
+                arguments.push_str(&format!(".TP\n\\fB{}\\fR\n{}\n", roff(arg), roff(text)));
+            }
+            _ => {}
+        }
     }
 
-    let mb_commit = repo.find_commit(mb)?;
-    // Soft reset to the merge base
-    repo.reset(mb_commit.as_object(), git2::ResetType::Soft, None)?;
+    format!(
+        ".TH GIT\\-SQUASH 1 \"\" \"git\\-squash {}\"\n\
+         .SH NAME\n\
+         git\\-squash \\- squash all commits on a branch relative to another branch\n\
+         .SH SYNOPSIS\n\
+         {}\
+         .SH DESCRIPTION\n\
+         The commits of the current branch that are not on the base are replaced with a single \
+         commit with the same tree, reusing the message of the oldest of them.\n\
+         .SH OPTIONS\n\
+         {}\
+         .SH EXIT STATUS\n\
+         .TP\n\
+         0\n\
+         The branch was squashed, or there was nothing to squash.\n\
+         .TP\n\
+         1\n\
+         An error, nothing was changed unless the error says otherwise.\n\
+         .TP\n\
+         2\n\
+         There was nothing to squash and {} was given.\n\
+         .SH EXAMPLES\n\
+         .TP\n\
+         {}\n\
+         Squash the current branch on top of master.\n\
+         .TP\n\
+         {}\n\
+         List what would be squashed without changing anything.\n\
+         .TP\n\
+         {}\n\
+         Squash with the message of the newest commit and edit it first.\n\
+         .TP\n\
+         {}\n\
+         Squash the last three commits only.\n",
+        crate_version!(),
+        synopsis,
+        arguments,
+        roff("--require-squash"),
+        roff("git squash master"),
+        roff("git squash --dry-run master"),
+        roff("git squash --message-from newest --edit master"),
+        roff("git squash -n 3"),
+    )
+}
 
-    // Write the index to a tree
-    let tree_oid = repo.index()?.write_tree()?;
+// The arguments that find the repository, which every subcommand takes
+fn repo_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("directory")
+            .short("C")
+            .value_name("path")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Run as if git-squash was started in <path>"),
+        Arg::with_name("git-dir")
+            .long("git-dir")
+            .value_name("path")
+            .help("The repository to use instead of discovering one, like GIT_DIR"),
+    ]
+}
 
-    // The commit on this branch on top of the merge base which
-    // we can reuse for the commit message.
-    let last_commit = repo.find_commit(*commits_to_squash.last().unwrap())?;
+// The arguments of squash, which preview shares
+fn squash_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    let mut args = repo_args();
+    args.extend(vec![
+        Arg::with_name("branch")
+            .help(
+                "The upstream branch to squash commits of the current branch on to. \
+                 Defaults to branch.<name>.squashBase or squash.base from git config, the upstream of the \
+                 current branch, or the default branch of the repository.",
+            )
+            .index(1),
+        Arg::with_name("branches")
+            .index(2)
+            .multiple(true)
+            .help("Squash these local branches onto the base in turn, like giving each with --branch"),
+        Arg::with_name("squash-branch")
+            .long("branch")
+            .value_name("name")
+            .multiple(true)
+            .number_of_values(1)
+            .help(
+                "Squash this local branch instead of the current one, without checking it out. \
+                 Given more than once, each branch is squashed onto the same base in turn.",
+            ),
+        Arg::with_name("keep-going")
+            .long("keep-going")
+            .help("Squash the rest of the branches after one fails"),
+        Arg::with_name("count")
+            .short("n")
+            .long("count")
+            .value_name("n")
+            .validator(|n| match n.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(()),
+                _ => Err("must be a positive number".to_string()),
+            })
+            .help(
+                "Only squash the last <n> commits of the branch, it is an error \
+                 if the branch has fewer commits on top of the base",
+            ),
+        Arg::with_name("into")
+            .long("into")
+            .value_name("commit")
+            .conflicts_with("count")
+            .help(
+                "Fold the commits after <commit> on the branch into it, keeping its message, \
+                 and leave the commits before it alone",
+            ),
+        Arg::with_name("split-at")
+            .long("split-at")
+            .value_name("commit")
+            .conflicts_with_all(&["into", "count", "since"])
+            .help(
+                "Keep the commits of the branch up to <commit> and squash only the ones after \
+                 it onto it",
+            ),
+        Arg::with_name("range")
+            .long("range")
+            .value_name("base>..<tip")
+            .conflicts_with("branch")
+            .help(
+                "Squash the commits reachable from <tip> but not <base> onto <base>, \
+                 instead of the branch onto its merge base",
+            ),
+        Arg::with_name("max-commits")
+            .long("max-commits")
+            .value_name("n")
+            .validator(|n| match n.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(()),
+                _ => Err("must be a positive number".to_string()),
+            })
+            .help(
+                "Refuse to squash more than <n> commits, in case the wrong base was picked. \
+                 Defaults to squash.maxCommits.",
+            ),
+        Arg::with_name("first-parent")
+            .long("first-parent")
+            .help(
+                "Only follow the first parent of merges when finding the commits to squash, \
+                 like git log --first-parent. Commits that came in through merges are not \
+                 counted, their changes are still part of the squashed tree.",
+            ),
+        Arg::with_name("since")
+            .long("since")
+            .value_name("date")
+            .help(
+                "Only squash the commits with a committer date newer than <date>, older \
+                 commits are left on the branch",
+            ),
+        Arg::with_name("allow-unsigned-squash")
+            .long("allow-unsigned-squash")
+            .help("Do not warn when signed commits are squashed into an unsigned commit"),
+        Arg::with_name("verify-signatures")
+            .long("verify-signatures")
+            .help(
+                "Refuse to squash unless every squashed commit has a good GPG or SSH signature \
+                 by a trusted key, like git merge --verify-signatures",
+            ),
+        Arg::with_name("author")
+            .long("author")
+            .value_name("author")
+            .help("Override the author of the squashed commit, in the form 'Name <email>'"),
+        Arg::with_name("reflog-message")
+            .long("reflog-message")
+            .value_name("message")
+            .validator(|m| {
+                if m.trim().is_empty() {
+                    Err("must not be empty".to_string())
+                } else {
+                    Ok(())
+                }
+            })
+            .help("Use <message> for the reflog entry instead of describing the squash"),
+        Arg::with_name("allow-foreign-authors")
+            .long("allow-foreign-authors")
+            .help("Squash commits by authors other than the one of the squashed commit"),
+        Arg::with_name("committer-date-is-author-date")
+            .long("committer-date-is-author-date")
+            .help(
+                "Use the author date of the squashed commit as its committer date, like git \
+                 rebase --committer-date-is-author-date",
+            ),
+        Arg::with_name("protect")
+            .long("protect")
+            .value_name("pattern")
+            .multiple(true)
+            .number_of_values(1)
+            .help(
+                "Refuse to squash branches matching <pattern>, a glob. Can be given more \
+                 than once and defaults to squash.protectedBranches or else main and master.",
+            ),
+        Arg::with_name("no-protect")
+            .long("no-protect")
+            .conflicts_with("protect")
+            .help("Squash the branch even if it is protected"),
+        Arg::with_name("keep-author")
+            .long("keep-author")
+            .conflicts_with("author")
+            .help(
+                "Keep the author and author date of the commit the message is taken from \
+                 instead of using your own",
+            ),
+        Arg::with_name("date")
+            .long("date")
+            .value_name("date")
+            .help("Override the author date of the squashed commit"),
+        Arg::with_name("gpg-sign")
+            .short("S")
+            .long("gpg-sign")
+            .value_name("keyid")
+            .min_values(0)
+            .require_equals(true)
+            .help("GPG sign the squashed commit, the default when squash.gpgSign or commit.gpgsign is set"),
+        Arg::with_name("no-gpg-sign")
+            .long("no-gpg-sign")
+            .conflicts_with("gpg-sign")
+            .help("Do not sign the squashed commit even if squash.gpgSign or commit.gpgsign is set"),
+        Arg::with_name("gpg-program")
+            .long("gpg-program")
+            .value_name("path")
+            .help(
+                "Sign and check signatures with this program instead of gpg.program or gpg, \
+                 which uses the keyring in GNUPGHOME",
+            ),
+        Arg::with_name("allow-empty-message")
+            .long("allow-empty-message")
+            .help("Create the squashed commit even if its message ends up empty"),
+        Arg::with_name("collect-trailers")
+            .long("collect-trailers")
+            .help(
+                "Merge the trailers of all the squashed commits, like Reviewed-by, into the \
+                 trailers of the message, dropping duplicates",
+            ),
+        Arg::with_name("no-backup")
+            .long("no-backup")
+            .help("Do not keep the tip from before the squash in refs/squash-backup/<branch>"),
+        Arg::with_name("no-verify")
+            .long("no-verify")
+            .help("Do not run the commit-msg hook on the squashed message"),
+        Arg::with_name("record-squashed")
+            .long("record-squashed")
+            .help("Add a Squashed-commit trailer with the full id of each squashed commit"),
+        Arg::with_name("resign")
+            .long("resign")
+            .conflicts_with("no-gpg-sign")
+            .help(
+                "Sign the squashed commit with user.signingkey if any of the squashed commits \
+                 were signed",
+            ),
+        Arg::with_name("edit")
+            .short("e")
+            .long("edit")
+            .help("Edit the message of the squashed commit, squash.edit makes this the default"),
+        Arg::with_name("no-edit")
+            .long("no-edit")
+            .conflicts_with("edit")
+            .help("Use the message as it is even if squash.edit is set"),
+        Arg::with_name("cleanup")
+            .long("cleanup")
+            .value_name("mode")
+            .possible_values(&["strip", "whitespace", "scissors", "verbatim", "default"])
+            .help(
+                "How to clean up the message like git commit --cleanup, scissors cuts an \
+                 edited message at a scissors line with the squashed messages below it. \
+                 Defaults to commit.cleanup.",
+            ),
+        Arg::with_name("message-from")
+            .long("message-from")
+            .value_name("newest|oldest|rev")
+            .help("The squashed commit whose message is reused for the new commit")
+            .default_value("oldest"),
+        Arg::with_name("file")
+            .short("F")
+            .long("file")
+            .value_name("path")
+            .help(
+                "Take the message of the squashed commit from <path>, or stdin for -, \
+                 instead of reusing one",
+            ),
+        Arg::with_name("reuse-message")
+            .long("reuse-message")
+            .value_name("commit")
+            .conflicts_with_all(&["file", "reedit-message"])
+            .help(
+                "Reuse the message of <commit>, which does not have to be one of the squashed \
+                 commits, like git commit -C",
+            ),
+        Arg::with_name("reedit-message")
+            .short("c")
+            .long("reedit-message")
+            .value_name("commit")
+            .conflicts_with_all(&["file", "no-edit"])
+            .help("Like --reuse-message but edit the message first, like git commit -c"),
+        Arg::with_name("post-squash")
+            .long("post-squash")
+            .value_name("command")
+            .help(
+                "Run a shell command after squashing, with GIT_SQUASH_OLD_OID, \
+                 GIT_SQUASH_NEW_OID, GIT_SQUASH_BASE and GIT_SQUASH_BRANCH set",
+            ),
+        Arg::with_name("post-squash-rollback")
+            .long("post-squash-rollback")
+            .requires("post-squash")
+            .help("Undo the squash if the post-squash command fails"),
+        Arg::with_name("path")
+            .long("path")
+            .value_name("path")
+            .help(
+                "Only squash the changes under <path>, relative to the top of the repository. \
+                 Everything else in the squashed commit stays as it is in the base.",
+            ),
+        Arg::with_name("autosquash")
+            .long("autosquash")
+            .help(
+                "Only fold fixup!, squash! and amend! commits into the commits they name, \
+                 like git rebase --autosquash, instead of squashing the whole branch",
+            ),
+        Arg::with_name("onto-base")
+            .long("onto")
+            .conflicts_with_all(&["count", "into", "split-at", "since"])
+            .help(
+                "Create the squashed commit on the tip of the base instead of the merge base, \
+                 failing without changing anything if the changes conflict",
+            ),
+        Arg::with_name("new-branch")
+            .long("new-branch")
+            .visible_alias("onto-new-branch")
+            .value_name("name")
+            .help(
+                "Point a new branch at the squashed commit and leave the squashed branch, \
+                 the index and the working copy alone",
+            ),
+        Arg::with_name("force")
+            .long("force")
+            .help(
+                "Replace the branch named by --new-branch if it already exists, move the \
+                 --advance branch even if it does not fast-forward, and squash a branch checked \
+                 out in another worktree with only a warning",
+            ),
+        Arg::with_name("advance")
+            .long("advance")
+            .value_name("branch")
+            .help(
+                "Fast-forward <branch> to the squashed commit afterwards, like an integration \
+                 branch the squash goes on top of",
+            ),
+        Arg::with_name("push")
+            .long("push")
+            .help(
+                "Force push the squashed branch to its upstream, only if the remote branch \
+                 has not moved since it was last pushed",
+            ),
+        Arg::with_name("remote")
+            .long("remote")
+            .value_name("remote")
+            .requires("push")
+            .help("Push to the branch of the same name on <remote> instead of the upstream"),
+        Arg::with_name("interactive")
+            .short("i")
+            .long("interactive")
+            .conflicts_with("autosquash")
+            .help(
+                "Choose which commits to keep, which to fold into the commit before them and \
+                 which to drop in a todo list opened in the editor",
+            ),
+        Arg::with_name("reuse-existing")
+            .long("reuse-existing")
+            .help(
+                "Do nothing if a commit on the branch directly on top of the base already \
+                 has the tree the squashed commit would have",
+            ),
+        Arg::with_name("strict")
+            .long("strict")
+            .help("Count untracked files as changes that keep the repo from being squashed"),
+        Arg::with_name("allow-dirty")
+            .long("allow-dirty")
+            .help(
+                "Squash even with local changes. Staged changes become part of the squashed \
+                 commit, other changes stay in the working copy.",
+            ),
+        Arg::with_name("wip")
+            .long("wip")
+            .value_name("all")
+            .min_values(0)
+            .require_equals(true)
+            .possible_values(&["all"])
+            .conflicts_with_all(&[
+                "squash-branch",
+                "branches",
+                "new-branch",
+                "autostash",
+                "autosquash",
+                "interactive",
+                "skip-dirty-check",
+            ])
+            .help(
+                "Put the changes to tracked files in the working copy, and with =all the \
+                 untracked files too, in the squashed commit instead of refusing to squash",
+            ),
+        Arg::with_name("skip-dirty-check")
+            .long("skip-dirty-check")
+            .conflicts_with("autostash")
+            .help(
+                "Do not scan the working copy for local changes, which is slow in huge \
+                 repositories. Only use it when the working copy is clean, staged changes \
+                 would be folded into the squashed commit.",
+            ),
+        Arg::with_name("autostash")
+            .long("autostash")
+            .help(
+                "Stash local changes before squashing and apply them again afterwards, \
+                 rebase.autoStash makes this the default",
+            ),
+        Arg::with_name("no-autostash")
+            .long("no-autostash")
+            .conflicts_with("autostash")
+            .help("Refuse to squash with local changes even if rebase.autoStash is set"),
+        Arg::with_name("ignore-submodules")
+            .long("ignore-submodules")
+            .value_name("when")
+            .min_values(0)
+            .require_equals(true)
+            .possible_values(&["none", "untracked", "dirty", "all"])
+            .help(
+                "Ignore changes to submodules when checking for local changes, like git \
+                 status --ignore-submodules. <when> defaults to all.",
+            ),
+        Arg::with_name("verify-clean-after")
+            .long("verify-clean-after")
+            .help("Fail if the index or working copy do not match the squashed commit afterwards"),
+        Arg::with_name("copy-notes")
+            .long("copy-notes")
+            .value_name("ref")
+            .min_values(0)
+            .require_equals(true)
+            .help(
+                "Attach the notes of the squashed commits to the squashed commit, from <ref> or \
+                 notes.rewriteRef, or else the default notes ref",
+            ),
+        Arg::with_name("no-verify-tree")
+            .long("no-verify-tree")
+            .help(
+                "Do not check that the squashed commit has the same tree as the branch \
+                 before moving the branch",
+            ),
+        Arg::with_name("rewrite-single")
+            .long("rewrite-single")
+            .help(
+                "Rewrite a branch with a single commit anyway, to change its message. Implied \
+                 by --file, --reuse-message, --edit and --collect-trailers.",
+            ),
+        Arg::with_name("idempotent")
+            .long("idempotent")
+            .help(
+                "Succeed without doing anything when the branch is already a single commit \
+                 that squashing would recreate, even with --require-squash",
+            ),
+        Arg::with_name("require-squash")
+            .long("require-squash")
+            .help("Exit with status 2 instead of 0 when there is nothing to squash"),
+        Arg::with_name("dry-run")
+            .long("dry-run")
+            .help("List the commits that would be squashed without changing anything"),
+        Arg::with_name("preview")
+            .long("preview")
+            .visible_alias("show-message")
+            .conflicts_with_all(&["dry-run", "autosquash", "interactive"])
+            .help("Print the message the squashed commit would get without changing anything"),
+        Arg::with_name("print-base")
+            .long("print-base")
+            .help(
+                "Print the merge base with the base and how many commits are on top of it, \
+                 without squashing",
+            ),
+        Arg::with_name("stat-only")
+            .long("stat-only")
+            .help("Like --dry-run but only print a single machine readable summary line"),
+        Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .help(
+                "Explain how the base of the squash was chosen, and show the diff of the \
+                 squash below the message when editing it like git commit --verbose",
+            ),
+        Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .conflicts_with("verbose")
+            .help("Only print errors and warnings"),
+        Arg::with_name("quiet-if-noop")
+            .long("quiet-if-noop")
+            .help(
+                "Do not print anything when there is nothing to squash, like on a branch \
+                 with a single commit",
+            ),
+        Arg::with_name("abbrev")
+            .long("abbrev")
+            .value_name("n")
+            .validator(|n| match n.parse::<usize>() {
+                Ok(n) if (4..=40).contains(&n) => Ok(()),
+                _ => Err("must be a number from 4 to 40".to_string()),
+            })
+            .help("Show commit ids with <n> digits, core.abbrev or 7 by default"),
+        Arg::with_name("no-abbrev")
+            .long("no-abbrev")
+            .conflicts_with("abbrev")
+            .help("Show full commit ids"),
+        Arg::with_name("log-level")
+            .long("log-level")
+            .value_name("level")
+            .possible_values(&["error", "warn", "info", "debug", "trace"])
+            .help("Log what git-squash does at <level> to stderr, overriding RUST_LOG"),
+        Arg::with_name("generate-man")
+            .long("generate-man")
+            .value_name("path")
+            .min_values(0)
+            .max_values(1)
+            .hidden(true)
+            .help("Write the man page to <path>, or print it"),
+        Arg::with_name("generate-completions")
+            .long("generate-completions")
+            .value_name("shell")
+            .possible_values(&Shell::variants())
+            .hidden(true)
+            .help("Print a completion script for <shell>"),
+        Arg::with_name("set-base")
+            .long("set-base")
+            .value_name("base")
+            .help("Store the base to squash the current branch on to in branch.<name>.squashBase"),
+    ]);
+    args
+}
 
-    // Create the commit
-    let sig = repo.signature()?;
-    repo.commit(
-        Some("HEAD"),
-        &sig,
-        &sig,
-        last_commit.message().unwrap(),
-        &repo.find_tree(tree_oid)?,
-        &[&mb_commit],
-    )?;
+fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("git-squash")
+        .version(crate_version!())
+        .about("Utility to squash all commits on a branch relative to another branch")
+        .after_help("Without a subcommand the arguments are those of squash.")
+        .subcommand(
+            SubCommand::with_name("squash")
+                .about("Squash the commits of the current branch, the default")
+                .args(&squash_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("preview")
+                .about("Print the message the squashed commit would get, like squash --preview")
+                .args(&squash_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("undo")
+                .about("Put a branch back where it was before it was last squashed")
+                .args(&repo_args())
+                .arg(
+                    Arg::with_name("branch")
+                        .index(1)
+                        .help("The branch to restore, the current one by default"),
+                )
+                .arg(
+                    Arg::with_name("quiet")
+                        .short("q")
+                        .long("quiet")
+                        .help("Only print errors"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("prune-backups")
+                .about("Delete the backups of squashed branches that contain them again")
+                .args(&repo_args())
+                .arg(
+                    Arg::with_name("quiet")
+                        .short("q")
+                        .long("quiet")
+                        .help("Only print errors"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Print a shell completion script")
+                .arg(
+                    Arg::with_name("shell")
+                        .required(true)
+                        .possible_values(&Shell::variants()),
+                ),
+        )
+}
 
-    Ok(())
+// git-squash <branch> with the arguments of squash but no subcommand, as it
+// was before there were subcommands, is squash. Like with git, -C and
+// --git-dir can come before the subcommand, which is moved in front of them
+// for clap.
+fn with_default_subcommand(mut args: Vec<OsString>) -> Vec<OsString> {
+    let mut position = 1;
+    while let Some(arg) = args.get(position).and_then(|a| a.to_str()) {
+        position += match arg {
+            "-C" | "--git-dir" => 2,
+            _ if arg.starts_with("-C") || arg.starts_with("--git-dir=") => 1,
+            _ => break,
+        };
+    }
+    let explicit = match args.get(position).and_then(|a| a.to_str()) {
+        Some(arg) => [
+            "squash",
+            "preview",
+            "undo",
+            "prune-backups",
+            "completions",
+            "help",
+            "-h",
+            "--help",
+            "-V",
+            "--version",
+        ]
+        .contains(&arg),
+        None => false,
+    };
+    if explicit {
+        let command = args.remove(position);
+        args.insert(1, command);
+    } else {
+        args.insert(1, OsString::from("squash"));
+    }
+    args
 }
 
 fn main() {
-    let app = App::new("git-squash")
-        .version(crate_version!())
-        .about("Utility to squash all commits on a branch relative to another branch")
-        .arg(
-            Arg::with_name("branch")
-                .required(true)
-                .help("The upstream branch to squash commits of the current branch on to.")
-                .index(1)
-                .default_value("master"),
+    let matches = cli()
+        .get_matches_from_safe(with_default_subcommand(env::args_os().collect()))
+        .unwrap_or_else(|e| e.exit());
+    let (command, matches) = match matches.subcommand() {
+        (command, Some(matches)) => (command, matches),
+        _ => unreachable!(),
+    };
+
+    if command == "completions" {
+        print_completions(
+            &mut cli(),
+            matches.value_of("shell").unwrap().parse().unwrap(),
         );
+        return;
+    }
+    if matches.is_present("generate-man") {
+        let squash = App::new("git-squash")
+            .version(crate_version!())
+            .args(&squash_args());
+        let man = man_page(&squash);
+        let written = match matches.value_of("generate-man") {
+            Some(path) => fs::write(path, man),
+            None => io::stdout().write_all(man.as_bytes()),
+        };
+        if let Err(e) = written {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    if let Some(shell) = matches.value_of("generate-completions") {
+        print_completions(&mut cli(), shell.parse().unwrap());
+        return;
+    }
 
-    let matches = app.get_matches();
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = matches.value_of("log-level") {
+        logger.filter_level(level.parse().unwrap());
+    }
+    logger.init();
 
-    let branch = matches.value_of("branch").unwrap();
+    let dir = match start_dir(matches.values_of("directory").into_iter().flatten()) {
+        Ok(dir) => dir,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(e.exit_code());
+        }
+    };
 
-    match squash(branch) {
-        Ok(()) => {}
+    if command == "undo" {
+        let opts = SquashOptions {
+            dir,
+            git_dir: matches.value_of("git-dir").map(PathBuf::from),
+            ..Default::default()
+        };
+        match open_repo(&opts).and_then(|repo| undo(&repo, matches.value_of("branch"))) {
+            Ok((branch, oid)) => {
+                if !matches.is_present("quiet") {
+                    println!("Restored {} to {}", branch, oid);
+                }
+            }
+            Err(e) => {
+                eprintln!("error: {}", e);
+                process::exit(e.exit_code());
+            }
+        }
+        return;
+    }
+
+    if command == "prune-backups" {
+        let opts = SquashOptions {
+            dir,
+            git_dir: matches.value_of("git-dir").map(PathBuf::from),
+            ..Default::default()
+        };
+        let pruned = open_repo(&opts).and_then(|repo| Ok((prune_backups(&repo)?, repo)));
+        match pruned {
+            Ok((pruned, repo)) => {
+                if !matches.is_present("quiet") {
+                    for (refname, oid) in pruned {
+                        println!("Deleted {} (was {})", refname, abbrev_id(&repo, oid, None));
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("error: {}", e);
+                process::exit(e.exit_code());
+            }
+        }
+        return;
+    }
+
+    let mut opts = SquashOptions {
+        dir,
+        git_dir: matches.value_of("git-dir").map(PathBuf::from),
+        base: matches.value_of("branch").map(|b| b.to_string()),
+        branch: matches
+            .value_of("squash-branch")
+            .or_else(|| matches.value_of("branches"))
+            .map(|b| b.to_string()),
+        message_from: MessageSource::parse(matches.value_of("message-from").unwrap()),
+        message_file: matches.value_of("file").map(PathBuf::from),
+        reuse_message: matches
+            .value_of("reuse-message")
+            .or_else(|| matches.value_of("reedit-message"))
+            .map(String::from),
+        count: matches.value_of("count").map(|n| n.parse().unwrap()),
+        into: matches.value_of("into").map(String::from),
+        split_at: matches.value_of("split-at").map(String::from),
+        range: matches.value_of("range").map(String::from),
+        max_commits: matches.value_of("max-commits").map(|n| n.parse().unwrap()),
+        since: matches.value_of("since").map(String::from),
+        first_parent: matches.is_present("first-parent"),
+        allow_unsigned_squash: matches.is_present("allow-unsigned-squash"),
+        verify_signatures: matches.is_present("verify-signatures"),
+        author: matches.value_of("author").map(|a| a.to_string()),
+        keep_author: matches.is_present("keep-author"),
+        allow_foreign_authors: matches.is_present("allow-foreign-authors"),
+        committer_date_is_author_date: matches.is_present("committer-date-is-author-date"),
+        protect: matches
+            .values_of("protect")
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or_default(),
+        no_protect: matches.is_present("no-protect"),
+        reflog_message: matches.value_of("reflog-message").map(String::from),
+        date: matches.value_of("date").map(|d| d.to_string()),
+        gpg_sign: if matches.is_present("gpg-sign") {
+            Some(true)
+        } else if matches.is_present("no-gpg-sign") {
+            Some(false)
+        } else {
+            None
+        },
+        gpg_key: matches.value_of("gpg-sign").map(|k| k.to_string()),
+        gpg_program: matches.value_of("gpg-program").map(String::from),
+        resign: matches.is_present("resign"),
+        cleanup: matches.value_of("cleanup").and_then(Cleanup::parse),
+        collect_trailers: matches.is_present("collect-trailers"),
+        record_squashed: matches.is_present("record-squashed"),
+        no_verify: matches.is_present("no-verify"),
+        no_backup: matches.is_present("no-backup"),
+        allow_empty_message: matches.is_present("allow-empty-message"),
+        edit: if matches.is_present("edit") || matches.is_present("reedit-message") {
+            Some(true)
+        } else if matches.is_present("no-edit") {
+            Some(false)
+        } else {
+            None
+        },
+        post_squash: matches.value_of("post-squash").map(|c| c.to_string()),
+        post_squash_rollback: matches.is_present("post-squash-rollback"),
+        require_squash: matches.is_present("require-squash"),
+        verify_clean_after: matches.is_present("verify-clean-after"),
+        no_verify_tree: matches.is_present("no-verify-tree"),
+        copy_notes: matches.is_present("copy-notes"),
+        notes_ref: matches.value_of("copy-notes").map(String::from),
+        strict: matches.is_present("strict"),
+        allow_dirty: matches.is_present("allow-dirty"),
+        wip: matches.is_present("wip"),
+        wip_untracked: matches.value_of("wip") == Some("all"),
+        skip_dirty_check: matches.is_present("skip-dirty-check"),
+        ignore_submodules: if matches.is_present("ignore-submodules") {
+            Some(
+                matches
+                    .value_of("ignore-submodules")
+                    .unwrap_or("all")
+                    .to_string(),
+            )
+        } else {
+            None
+        },
+        autostash: if matches.is_present("autostash") {
+            Some(true)
+        } else if matches.is_present("no-autostash") {
+            Some(false)
+        } else {
+            None
+        },
+        reuse_existing: matches.is_present("reuse-existing"),
+        idempotent: matches.is_present("idempotent"),
+        rewrite_single: matches.is_present("rewrite-single"),
+        autosquash: matches.is_present("autosquash"),
+        interactive: matches.is_present("interactive"),
+        onto_base: matches.is_present("onto-base"),
+        new_branch: matches.value_of("new-branch").map(String::from),
+        force: matches.is_present("force"),
+        advance: matches.value_of("advance").map(String::from),
+        push: matches.is_present("push"),
+        remote: matches.value_of("remote").map(String::from),
+        path: matches.value_of("path").map(String::from),
+        dry_run: matches.is_present("dry-run") || matches.is_present("stat-only"),
+        preview: command == "preview" || matches.is_present("preview"),
+        print_base: matches.is_present("print-base"),
+        stat_only: matches.is_present("stat-only"),
+        verbose: matches.is_present("verbose"),
+        quiet: matches.is_present("quiet"),
+        quiet_if_noop: matches.is_present("quiet-if-noop"),
+        abbrev: if matches.is_present("no-abbrev") {
+            Some(40)
+        } else {
+            matches.value_of("abbrev").map(|n| n.parse().unwrap())
+        },
+        reporter: Some(Box::new(Terminal)),
+    };
+
+    if let Some(base) = matches.value_of("set-base") {
+        if let Err(e) = set_base(&opts, base) {
+            eprintln!("error: {}", e);
+            process::exit(e.exit_code());
+        }
+        return;
+    }
+
+    let branches: Vec<&str> = matches
+        .values_of("squash-branch")
+        .into_iter()
+        .flatten()
+        .chain(matches.values_of("branches").into_iter().flatten())
+        .collect();
+    if matches.is_present("keep-going") && branches.is_empty() {
+        eprintln!(
+            "error: --keep-going needs the branches to squash, after the base or with --branch"
+        );
+        process::exit(1);
+    }
+    if branches.len() > 1 {
+        for (option, given) in [
+            ("--new-branch", opts.new_branch.is_some()),
+            ("--advance", opts.advance.is_some()),
+        ] {
+            if given {
+                eprintln!(
+                    "error: {} cannot be used with more than one branch to squash",
+                    option
+                );
+                process::exit(1);
+            }
+        }
+        let code = squash_branches(&mut opts, &branches, matches.is_present("keep-going"));
+        process::exit(code);
+    }
+
+    match run(&opts) {
+        Ok(outcome) => match open_repo(&opts) {
+            Ok(repo) => report(&repo, &opts, &outcome),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                process::exit(e.exit_code());
+            }
+        },
         Err(e) => {
             eprintln!("error: {}", e);
-            process::exit(1);
+            process::exit(e.exit_code());
         }
     }
 }
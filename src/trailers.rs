@@ -0,0 +1,195 @@
+// Parsing of the trailers at the end of a commit message, the "Key: value"
+// lines git interpret-trailers reads, like "Reviewed-by: A U Thor
+// <author@example.com>".
+
+pub struct Trailer {
+    pub key: String,
+    pub value: String,
+}
+
+// "Key: value", the key made of letters, digits and dashes
+fn trailer_line(line: &str) -> Option<Trailer> {
+    let colon = line.find(':')?;
+    let key = line[..colon].trim_end();
+    if key.is_empty()
+        || key.starts_with('-')
+        || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return None;
+    }
+
+    Some(Trailer {
+        key: key.to_string(),
+        value: line[colon + 1..].trim().to_string(),
+    })
+}
+
+// Split a message into its text and the trailers of its last paragraph.
+// The paragraph is only a trailer block when all its lines are trailers, or
+// continue the one before with leading whitespace, and the subject never
+// is one.
+pub fn parse(message: &str) -> (&str, Vec<Trailer>) {
+    let message = message.trim_end();
+    let start = match message.rfind("\n\n") {
+        Some(end) => end + 2,
+        None => return (message, Vec::new()),
+    };
+
+    let mut trailers: Vec<Trailer> = Vec::new();
+    for line in message[start..].lines() {
+        if line.starts_with([' ', '\t']) {
+            match trailers.last_mut() {
+                Some(trailer) => {
+                    trailer.value.push('\n');
+                    trailer.value.push_str(line);
+                }
+                None => return (message, Vec::new()),
+            }
+            continue;
+        }
+        match trailer_line(line) {
+            Some(trailer) => trailers.push(trailer),
+            None => return (message, Vec::new()),
+        }
+    }
+
+    (message[..start].trim_end(), trailers)
+}
+
+// Drop repeated trailers, the same key in any case with the same value,
+// and group the rest by key in the order the keys first appear
+pub fn merge(trailers: Vec<Trailer>) -> Vec<Trailer> {
+    let mut keys: Vec<String> = Vec::new();
+    let mut unique: Vec<Trailer> = Vec::new();
+    for trailer in trailers {
+        let key = trailer.key.to_ascii_lowercase();
+        if unique
+            .iter()
+            .any(|t| t.key.eq_ignore_ascii_case(&key) && t.value == trailer.value)
+        {
+            continue;
+        }
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+        unique.push(trailer);
+    }
+
+    let mut merged = Vec::new();
+    for key in keys {
+        let (group, rest): (Vec<Trailer>, Vec<Trailer>) = unique
+            .into_iter()
+            .partition(|t| t.key.eq_ignore_ascii_case(&key));
+        merged.extend(group);
+        unique = rest;
+    }
+    merged
+}
+
+// A message with the trailers as its last paragraph
+pub fn format(text: &str, trailers: &[Trailer]) -> String {
+    let mut message = text.trim_end().to_string();
+    if trailers.is_empty() {
+        message.push('\n');
+        return message;
+    }
+
+    message.push_str("\n\n");
+    for trailer in trailers {
+        message.push_str(&trailer.key);
+        message.push_str(": ");
+        message.push_str(&trailer.value);
+        message.push('\n');
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(trailers: &[Trailer]) -> Vec<(&str, &str)> {
+        trailers
+            .iter()
+            .map(|t| (t.key.as_str(), t.value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_several_trailers() {
+        let (text, trailers) = parse(
+            "Fix the thing\n\nSome details.\n\n\
+             Reviewed-by: A U Thor <author@example.com>\n\
+             Signed-off-by: C O Mitter <committer@example.com>\n\
+             Fixes: #12\n",
+        );
+        assert_eq!(text, "Fix the thing\n\nSome details.");
+        assert_eq!(
+            pairs(&trailers),
+            vec![
+                ("Reviewed-by", "A U Thor <author@example.com>"),
+                ("Signed-off-by", "C O Mitter <committer@example.com>"),
+                ("Fixes", "#12"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_continuation_lines() {
+        let (text, trailers) = parse("Subject\n\nNote: first line\n  second line\nFixes: #1\n");
+        assert_eq!(text, "Subject");
+        assert_eq!(
+            pairs(&trailers),
+            vec![("Note", "first line\n  second line"), ("Fixes", "#1")]
+        );
+    }
+
+    #[test]
+    fn parse_mixed_paragraph_is_not_trailers() {
+        let message = "Subject\n\nReviewed-by: A <a@example.com>\nnot a trailer at all\n";
+        let (text, trailers) = parse(message);
+        assert_eq!(text, message.trim_end());
+        assert!(trailers.is_empty());
+    }
+
+    #[test]
+    fn parse_continuation_without_trailer_is_not_trailers() {
+        let (_, trailers) = parse("Subject\n\n  indented text\nKey: value\n");
+        assert!(trailers.is_empty());
+    }
+
+    #[test]
+    fn parse_subject_only() {
+        let (text, trailers) = parse("Fixes: looks like a trailer\n");
+        assert_eq!(text, "Fixes: looks like a trailer");
+        assert!(trailers.is_empty());
+    }
+
+    #[test]
+    fn merge_drops_duplicates_and_groups_by_key() {
+        let (_, first) = parse("a\n\nSigned-off-by: A\nReviewed-by: B\n");
+        let (_, second) = parse("b\n\nsigned-off-by: A\nSigned-off-by: C\nreviewed-by: D\n");
+        let merged = merge(first.into_iter().chain(second).collect());
+        assert_eq!(
+            pairs(&merged),
+            vec![
+                ("Signed-off-by", "A"),
+                ("Signed-off-by", "C"),
+                ("Reviewed-by", "B"),
+                ("reviewed-by", "D"),
+            ]
+        );
+    }
+
+    #[test]
+    fn format_round_trip() {
+        let message = "Subject\n\nBody text.\n\nReviewed-by: A <a@example.com>\nFixes: #3\n";
+        let (text, trailers) = parse(message);
+        assert_eq!(format(text, &trailers), message);
+    }
+
+    #[test]
+    fn format_without_trailers() {
+        assert_eq!(format("Subject\n\nBody\n\n", &[]), "Subject\n\nBody\n");
+    }
+}